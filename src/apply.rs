@@ -0,0 +1,382 @@
+use std::{fs, io, thread};
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::SystemTime;
+use filetime::{FileTime, set_file_mtime, set_symlink_file_times};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use crate::copy::{copy_file, CopyMethod};
+use crate::delta::update_changed_blocks;
+use crate::differences::Difference;
+use crate::hardlinks::{link_group, LinkGroup};
+use crate::options::SyncOptions;
+use crate::progress::Progress;
+use crate::special::{recreate_special_file, special_kind};
+use crate::throttle::Throttle;
+use crate::versions::Versions;
+
+/// A single file system change in the target directory.
+/// Differences are broken down into operations, so that they can be executed by a worker pool.
+#[derive(Debug, Clone)]
+pub(crate) enum Operation {
+    CreateDir { from: String, to: String },
+    CopyFile { from: String, to: String, modified: SystemTime, replace: bool, link_group: Option<LinkGroup> },
+    RemoveFile { path: String },
+    RemoveDir { path: String },
+    MoveFile { from: String, to: String },
+    HardLink { existing: String, to: String, replace: bool },
+    CreateSpecial { from: String, to: String, modified: Option<SystemTime>, replace: bool },
+    RefuseSpecial { path: String, marker: &'static str },
+}
+
+impl Operation {
+    /// Stable name of the kind of operation, used in summaries and json output.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Operation::CreateDir { .. } => "create_dir",
+            Operation::CopyFile { .. } => "copy_file",
+            Operation::RemoveFile { .. } => "remove_file",
+            Operation::RemoveDir { .. } => "remove_dir",
+            Operation::MoveFile { .. } => "move_file",
+            Operation::HardLink { .. } => "hard_link",
+            Operation::CreateSpecial { .. } => "create_special",
+            Operation::RefuseSpecial { .. } => "refuse_special",
+        }
+    }
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Operation::CreateDir { from, to } => format!("Creating directory...:\n    '{from}' -> {to}"),
+            Operation::CopyFile { from, to, replace: true, .. } => format!("Replacing file...:\n    '{from}' -> {to}"),
+            Operation::CopyFile { from, to, replace: false, .. } => format!("Copying file...:\n    '{from}' -> {to}"),
+            Operation::RemoveFile { path } => format!("Removing file...: '{path}'"),
+            Operation::RemoveDir { path } => format!("Removing directory...: '{path}'"),
+            Operation::MoveFile { from, to } => format!("Moving file...:\n    '{from}' -> {to}"),
+            Operation::HardLink { existing, to, .. } => format!("Linking file...:\n    '{existing}' -> {to}"),
+            Operation::CreateSpecial { from, to, .. } => format!("Creating special file...:\n    '{from}' -> {to}"),
+            Operation::RefuseSpecial { path, marker } => format!("Refusing special file...: {marker}['{path}']"),
+        }
+    }
+}
+
+/// Outcome of one executed operation.
+#[derive(Debug, Clone)]
+pub(crate) struct OperationResult {
+    pub(crate) operation: Operation,
+    pub(crate) bytes: u64,
+    /// How the file content was transferred, for copied and replaced files.
+    pub(crate) method: Option<CopyMethod>,
+    pub(crate) error: Option<String>,
+}
+
+impl OperationResult {
+    pub(crate) fn describe(&self) -> String {
+        let (verb, paths) = match &self.operation {
+            Operation::CreateDir { from, to } => ("created directory", format!("'{from}' -> {to}")),
+            Operation::CopyFile { from, to, replace: true, .. } => ("replaced file", format!("'{from}' -> {to}")),
+            Operation::CopyFile { from, to, replace: false, .. } => ("copied file", format!("'{from}' -> {to}")),
+            Operation::RemoveFile { path } => ("removed file", format!("'{path}'")),
+            Operation::RemoveDir { path } => ("removed directory", format!("'{path}'")),
+            Operation::MoveFile { from, to } => ("moved file", format!("'{from}' -> {to}")),
+            Operation::HardLink { existing, to, .. } => ("linked file", format!("'{existing}' -> {to}")),
+            Operation::CreateSpecial { from, to, .. } => ("created special file", format!("'{from}' -> {to}")),
+            Operation::RefuseSpecial { path, marker } => ("refused special file", format!("{marker}['{path}']")),
+        };
+        match &self.error {
+            None => match self.method {
+                Some(method) => format!("Successfully {verb}: \n    {paths}\n    {} bytes written ({method})", self.bytes),
+                None => format!("Successfully {verb}: \n    {paths}\n    {} bytes written", self.bytes),
+            },
+            Some(e) => format!("Error, could not have {verb}: \n    {paths}\n    {e}"),
+        }
+    }
+}
+
+/// Aggregated results of applying a set of differences.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ApplyReport {
+    pub(crate) results: Vec<OperationResult>,
+}
+
+impl ApplyReport {
+    pub(crate) fn merge(&mut self, other: ApplyReport) {
+        self.results.extend(other.results);
+    }
+    pub(crate) fn failed(&self) -> usize {
+        self.results.iter().filter(|r| r.error.is_some()).count()
+    }
+    pub(crate) fn bytes_written(&self) -> u64 {
+        self.results.iter().map(|r| r.bytes).sum()
+    }
+    /// Counts and bytes per kind of operation, by name.
+    pub(crate) fn by_operation(&self) -> BTreeMap<&'static str, OperationCounts> {
+        let mut counts: BTreeMap<&'static str, OperationCounts> = BTreeMap::new();
+        for result in &self.results {
+            let entry = counts.entry(result.operation.name()).or_default();
+            entry.count += 1;
+            entry.failed += result.error.is_some() as usize;
+            entry.bytes += result.bytes;
+        }
+        counts
+    }
+    pub(crate) fn summary(&self) -> String {
+        let mut summary = format!("{} operations executed, {} failed, {} bytes written.", self.results.len(), self.failed(), self.bytes_written());
+        for (name, counts) in self.by_operation() {
+            summary += &format!("\n    {name}: {} executed, {} failed, {} bytes written", counts.count, counts.failed, counts.bytes);
+        }
+        summary
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct OperationCounts {
+    pub(crate) count: usize,
+    pub(crate) failed: usize,
+    pub(crate) bytes: u64,
+}
+
+/// Gets notified about operations while they are executed, possibly from several threads at once.
+pub(crate) trait ApplyObserver: Sync {
+    fn started(&self, operation: &Operation);
+    fn finished(&self, result: &OperationResult);
+    /// Called for differences that are applied right after they were found.
+    fn difference_found(&self, _difference: &Difference, _problem: Option<&str>) {}
+}
+
+/// Logs operations when they start at debug and when they finished at info, or at error if they failed.
+pub(crate) struct LoggingObserver;
+
+impl ApplyObserver for LoggingObserver {
+    fn started(&self, operation: &Operation) {
+        debug!("{}", operation.describe());
+    }
+    fn finished(&self, result: &OperationResult) {
+        if result.error.is_some() {
+            error!("{}", result.describe());
+        } else {
+            info!("{}", result.describe());
+        }
+    }
+}
+
+/// State shared by all operations of one run.
+pub(crate) struct ApplyContext {
+    workers: usize,
+    throttle: Throttle,
+    delta_min_size: Option<u64>,
+    /// Target path of the first copy of each hard linked source file, further links to it are linked to that copy.
+    linked: Mutex<HashMap<LinkGroup, String>>,
+    /// Set if replaced and deleted files are kept (SyncOptions::retention).
+    versions: Option<Versions>,
+    /// Followed by the ui, once cancelled the remaining operations are not executed.
+    progress: Option<Arc<Progress>>,
+}
+
+impl ApplyContext {
+    pub(crate) fn new(options: &SyncOptions, target_dir: &str) -> ApplyContext {
+        ApplyContext {
+            workers: options.workers,
+            throttle: Throttle::new(options.max_bytes_per_second, options.max_files_per_second),
+            delta_min_size: options.delta_min_size,
+            linked: Mutex::new(HashMap::new()),
+            versions: options.retention.map(|_| Versions::new(target_dir)),
+            progress: options.progress.clone(),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.progress.as_ref().is_some_and(|p| p.is_cancelled())
+    }
+
+    /// Removes a file of the target that is about to be replaced, or keeps it as a version.
+    fn discard(&self, path: &str) -> io::Result<()> {
+        match &self.versions {
+            Some(versions) => versions.preserve(path),
+            None => remove_file_if_exists(path),
+        }
+    }
+}
+
+/// Executes the given operations, file operations in parallel on up to `context.workers` threads.
+///
+/// Ordering constraints are kept by running in phases:
+/// files are moved within the target first (their directories exist on both sides),
+/// then directories are created (in plan order, so parents before children),
+/// then files are copied and removed by the worker pool,
+/// then hard links to copied files are created,
+/// and finally directories are removed (in plan order, so entries before their directory).
+/// If the progress is cancelled, the operations not yet started are left out of the report.
+pub(crate) fn execute_plan(plan: Vec<Operation>, context: &ApplyContext, observer: &dyn ApplyObserver) -> ApplyReport {
+    if let Some(progress) = &context.progress {
+        progress.operations_planned(plan.len());
+    }
+    let mut moves = Vec::new();
+    let mut create_dirs = Vec::new();
+    let mut file_operations = Vec::new();
+    let mut links = Vec::new();
+    let mut remove_dirs = Vec::new();
+    let mut first_copies: HashMap<LinkGroup, String> = HashMap::new();
+    for (i, operation) in plan.into_iter().enumerate() {
+        match operation {
+            Operation::MoveFile { .. } => moves.push((i, operation)),
+            Operation::CreateDir { .. } => create_dirs.push((i, operation)),
+            Operation::CopyFile { link_group: Some(group), ref to, replace, .. } => {
+                //only the first file of a group of hard links is copied, the others are linked to it
+                let existing = context.linked.lock().unwrap().get(&group).or(first_copies.get(&group)).cloned();
+                match existing {
+                    Some(existing) => links.push((i, Operation::HardLink { existing, to: to.clone(), replace })),
+                    None => {
+                        first_copies.insert(group, to.clone());
+                        file_operations.push((i, operation));
+                    }
+                }
+            }
+            Operation::CopyFile { .. } | Operation::RemoveFile { .. } | Operation::CreateSpecial { .. } | Operation::RefuseSpecial { .. } => file_operations.push((i, operation)),
+            Operation::HardLink { .. } => links.push((i, operation)),
+            Operation::RemoveDir { .. } => remove_dirs.push((i, operation)),
+        }
+    }
+
+    let mut results = Vec::with_capacity(moves.len() + create_dirs.len() + file_operations.len() + links.len() + remove_dirs.len());
+    results.extend(run_sequentially(moves, context, observer));
+    results.extend(run_sequentially(create_dirs, context, observer));
+    results.extend(run_in_pool(file_operations, context, observer));
+    results.extend(run_sequentially(links, context, observer));
+    results.extend(run_sequentially(remove_dirs, context, observer));
+
+    if context.is_cancelled() {
+        warn!("Warning, cancelled after {} operations, the remaining were not executed.", results.len());
+    }
+    results.sort_by_key(|(i, _)| *i);
+    ApplyReport { results: results.into_iter().map(|(_, r)| r).collect() }
+}
+
+fn run_sequentially(operations: Vec<(usize, Operation)>, context: &ApplyContext, observer: &dyn ApplyObserver) -> Vec<(usize, OperationResult)> {
+    operations.into_iter()
+        .take_while(|_| !context.is_cancelled())
+        .map(|(i, operation)| (i, run_observed(operation, context, observer)))
+        .collect()
+}
+
+fn run_in_pool(operations: Vec<(usize, Operation)>, context: &ApplyContext, observer: &dyn ApplyObserver) -> Vec<(usize, OperationResult)> {
+    let workers = context.workers.min(operations.len());
+    if workers <= 1 {
+        return run_sequentially(operations, context, observer);
+    }
+
+    let queue = Mutex::new(operations.into_iter());
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|s| {
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let queue = &queue;
+            s.spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                let Some((i, operation)) = next.filter(|_| !context.is_cancelled()) else { break };
+                sender.send((i, run_observed(operation, context, observer))).ok();
+            });
+        }
+    });
+    drop(sender);
+    receiver.into_iter().collect()
+}
+
+fn run_observed(operation: Operation, context: &ApplyContext, observer: &dyn ApplyObserver) -> OperationResult {
+    context.throttle.consume_file();
+    observer.started(&operation);
+    let result = match run(&operation, context) {
+        Ok((bytes, method)) => OperationResult { operation, bytes, method, error: None },
+        Err(e) => OperationResult { operation, bytes: 0, method: None, error: Some(e.to_string()) },
+    };
+    observer.finished(&result);
+    if let Some(progress) = &context.progress {
+        progress.operation_executed(result.bytes);
+    }
+    result
+}
+
+fn run(operation: &Operation, context: &ApplyContext) -> io::Result<(u64, Option<CopyMethod>)> {
+    match operation {
+        Operation::CreateDir { from, to } => {
+            match fs::create_dir(to) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+            Ok((0, None))
+        }
+        Operation::CopyFile { from, to, modified, replace, link_group } => {
+            let (bytes, method) = copy_file_update_time(*modified, from, to, *replace, context)?;
+            if let Some(group) = link_group {
+                context.linked.lock().unwrap().insert(*group, to.clone());
+            }
+            Ok((bytes, Some(method)))
+        }
+        Operation::RemoveFile { path } => match &context.versions {
+            Some(versions) => versions.preserve(path).map(|_| (0, None)),
+            None => fs::remove_file(path).map(|_| (0, None)),
+        },
+        Operation::RemoveDir { path } => fs::remove_dir(path).map(|_| (0, None)),
+        Operation::MoveFile { from, to } => fs::rename(from, to).map(|_| (0, None)),
+        Operation::HardLink { existing, to, replace } => {
+            if *replace {
+                context.discard(to)?;
+            }
+            fs::hard_link(existing, to).map(|_| (0, None))
+        }
+        Operation::CreateSpecial { from, to, modified, replace } => {
+            if *replace {
+                context.discard(to)?;
+            }
+            recreate_special_file(from, to)?;
+            if let Some(modified) = modified {
+                //set_file_mtime opens the file, which blocks on a fifo
+                let modified = FileTime::from(*modified);
+                set_symlink_file_times(to, modified, modified)?;
+            }
+            Ok((0, None))
+        }
+        Operation::RefuseSpecial { .. } => Err(io::Error::other("special files are not synchronized (policy: error)")),
+    }
+}
+
+fn copy_file_update_time(from_modified: SystemTime, from: &str, to: &str, replace: bool, context: &ApplyContext) -> io::Result<(u64, CopyMethod)> {
+    let replace = if replace && context.versions.is_some() {
+        //the replaced file is kept, so the copy is a new file
+        context.discard(to)?;
+        false
+    } else {
+        replace
+    };
+    if replace && fs::symlink_metadata(to).is_ok_and(|m| link_group(&m).is_some() || special_kind(&m).is_some()) {
+        //writing into the file would change all other links to it as well (or block on a fifo)
+        remove_file_if_exists(to)?;
+    }
+    let copied = match context.delta_min_size {
+        Some(min_size) if replace && is_at_least(from, min_size) && is_at_least(to, min_size) => {
+            match update_changed_blocks(from, to, &context.throttle) {
+                Ok(bytes) => (bytes, CopyMethod::Delta),
+                Err(e) => {
+                    warn!("Block update failed, copying full file instead: '{from}' -> {to}\n    {e}");
+                    copy_file(from, to, &context.throttle)?
+                }
+            }
+        }
+        _ => copy_file(from, to, &context.throttle)?,
+    };
+    set_file_mtime(to, FileTime::from(from_modified))?;
+    Ok(copied)
+}
+
+fn remove_file_if_exists(path: &str) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn is_at_least(path: &str, min_size: u64) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.len() >= min_size)
+}
+
@@ -0,0 +1,321 @@
+use std::io;
+use std::time::Duration;
+use clap::{ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use crate::config;
+use crate::hooks::Hook;
+use crate::options::{CaseSensitivity, Comparison, MoveDetection, MtimeTolerance, OutputFormat, parse_duration, parse_optional_duration, parse_optional_size, parse_rate, parse_retention, parse_size, SpecialFilePolicy, SyncOptions};
+
+/// Synchronizes a backup directory to the current state of a source directory.
+/// The program will NEVER change ANY file in the source directory.
+#[derive(Debug, Parser)]
+#[command(name = "directory_synchronizer", version)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Shows the differences between source and backup and the problems found, without changing anything
+    Analyze(RunArgs),
+    /// Applies all differences to the backup directory
+    #[command(alias = "just-do-it")]
+    Sync {
+        #[command(flatten)]
+        run: RunArgs,
+        /// Shows all differences and problems first and asks for each difference whether to apply it
+        #[arg(short, long)]
+        interactive: bool,
+    },
+    /// Starts a UI in which the differences to be applied can be selected (for a single backup directory)
+    Ui(RunArgs),
+    /// Starts a full-screen terminal UI in which the differences to be applied can be selected, e.g. over ssh (for a single backup directory)
+    Tui(RunArgs),
+    /// Keeps synchronizing: applies changes of the source as they happen (inotify) and compares the full trees periodically
+    Watch(RunArgs),
+    /// Checks whether the backups are up-to-date, exits with 0 if they are and with 1 if any is not
+    Verify(RunArgs),
+    /// Runs the profiles of the config file that have a schedule (interval or cron) until ended
+    Daemon {
+        #[command(flatten)]
+        options: OptionArgs,
+    },
+    /// Runs a profile of the config file
+    Run {
+        profile: String,
+        #[command(flatten)]
+        options: OptionArgs,
+    },
+    /// Writes a completion script for the shell to stdout
+    Completions {
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct RunArgs {
+    /// Directory to synchronize from
+    pub(crate) source: String,
+    /// Backup directories that are changed to equal the source, the source is only listed once for all of them
+    #[arg(required = true, value_name = "TARGET")]
+    pub(crate) targets: Vec<String>,
+    #[command(flatten)]
+    pub(crate) options: OptionArgs,
+}
+
+/// Options given on the command line, they take precedence over those from the config file.
+#[derive(Debug, Default, Args)]
+pub(crate) struct OptionArgs {
+    /// Number of files copied/removed in parallel [default: number of cpus, at most 8]
+    #[arg(long, value_name = "N")]
+    workers: Option<usize>,
+    /// Limits the bytes written per second, suffixes K, M and G are supported [default: unlimited]
+    #[arg(long, value_name = "N", value_parser = |v: &str| parse_size("--max-bytes-per-second", v))]
+    max_bytes_per_second: Option<u64>,
+    /// Limits the files copied/removed/created per second [default: unlimited]
+    #[arg(long, value_name = "N", value_parser = |v: &str| parse_rate("--max-files-per-second", v))]
+    max_files_per_second: Option<f64>,
+    /// Moves files and directories within the backup directory, if they were moved in the source: off, size-time or hash [default: off]
+    #[arg(long, value_name = "MODE", value_parser = |v: &str| MoveDetection::parse("--detect-moves", v))]
+    detect_moves: Option<MoveDetection>,
+    /// Replaced files of at least this size are updated by rewriting only changed blocks, e.g. 64M [default: off]
+    #[arg(long, value_name = "N", value_parser = |v: &str| parse_size("--delta-min-size", v))]
+    delta_min_size: Option<u64>,
+    /// How FIFOs, sockets and device nodes are handled: skip, recreate or error [default: skip]
+    #[arg(long, value_name = "POLICY", value_parser = |v: &str| SpecialFilePolicy::parse("--special-files", v))]
+    special_files: Option<SpecialFilePolicy>,
+    /// Does not descend into directories on other filesystems (mount points) within source or backup
+    #[arg(long)]
+    one_file_system: bool,
+    /// Modification times differing by at most this much are equal, e.g. 2s for FAT/exFAT, auto probes the backup directory [default: 0]
+    #[arg(long, value_name = "auto|DURATION", value_parser = |v: &str| MtimeTolerance::parse("--mtime-tolerance", v))]
+    mtime_tolerance: Option<MtimeTolerance>,
+    /// Whether names differing only in case denote the same file in the backup: auto, sensitive or insensitive, auto probes the backup directory [default: auto]
+    #[arg(long, value_name = "MODE", value_parser = |v: &str| CaseSensitivity::parse("--case-sensitivity", v))]
+    case_sensitivity: Option<CaseSensitivity>,
+    /// Names that are equal after Unicode normalization (NFC) denote the same file, e.g. for names from macOS
+    #[arg(long)]
+    normalize_unicode: bool,
+    /// Refuses to start if less than this could remain free in the backup directory while applying, e.g. 1G, or off [default: off]
+    #[arg(long, value_name = "N|off", value_parser = |v: &str| parse_optional_size("--free-space-margin", v).map(Margin))]
+    free_space_margin: Option<Margin>,
+    /// Writes differences, operations and a summary as newline-delimited json objects to stdout: text or json [default: text]
+    #[arg(long, value_name = "FORMAT", value_parser = |v: &str| OutputFormat::parse("--format", v))]
+    format: Option<OutputFormat>,
+    /// Shows more messages (e.g. how files are transferred), can be repeated (-vv)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Shows fewer messages, can be repeated (-qq)
+    #[arg(short, long, action = ArgAction::Count)]
+    quiet: u8,
+    /// Appends all messages with a timestamp to ".directory_synchronizer/log" in the backup directory
+    #[arg(long)]
+    log_file: bool,
+    /// Neither compares nor synchronizes matching paths, e.g. "*.tmp", "build/cache" or "cache/" (only directories), can be repeated
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// How files present on both sides are compared: mtime or checksum (content, ignoring modification times) [default: mtime]
+    #[arg(long, value_name = "METHOD", value_parser = |v: &str| Comparison::parse("--comparison", v))]
+    comparison: Option<Comparison>,
+    /// Keeps the files replaced and deleted by this many runs in ".directory_synchronizer/versions" in the backup directory, or off [default: off]
+    #[arg(long, value_name = "RUNS|off", value_parser = |v: &str| parse_retention("--retention", v).map(Retention))]
+    retention: Option<Retention>,
+    /// In watch mode, how long to wait for further changes before applying them, e.g. 500ms [default: 2s]
+    #[arg(long, value_name = "DURATION", value_parser = |v: &str| parse_duration("--debounce", v))]
+    debounce: Option<Duration>,
+    /// In watch mode, how often the full trees are compared to catch missed changes, e.g. 30m, or off [default: 1h]
+    #[arg(long, value_name = "DURATION|off", value_parser = |v: &str| parse_optional_duration("--reconcile-interval", v).map(Interval))]
+    reconcile_interval: Option<Interval>,
+    /// Runs the shell command before analyzing each backup directory, skips the directory if it fails (see Hooks below)
+    #[arg(long, value_name = "COMMAND")]
+    pre_scan_hook: Option<String>,
+    /// Runs the shell command once the differences of a backup directory are known
+    #[arg(long, value_name = "COMMAND")]
+    post_scan_hook: Option<String>,
+    /// Runs the shell command before applying differences to a backup directory, skips the directory if it fails
+    #[arg(long, value_name = "COMMAND")]
+    pre_apply_hook: Option<String>,
+    /// Runs the shell command at the end of every run that syncs a backup directory, also if it failed or nothing changed
+    #[arg(long, value_name = "COMMAND")]
+    post_apply_hook: Option<String>,
+}
+
+/// A free space margin, None if the check is off.
+#[derive(Debug, Clone, Copy)]
+struct Margin(Option<u64>);
+
+/// A retention, None if it is off.
+#[derive(Debug, Clone, Copy)]
+struct Retention(Option<usize>);
+
+/// A reconcile interval, None if it is off.
+#[derive(Debug, Clone, Copy)]
+struct Interval(Option<Duration>);
+
+impl OptionArgs {
+    pub(crate) fn apply_to(&self, options: &mut SyncOptions) {
+        if let Some(workers) = self.workers {
+            options.workers = workers.max(1);
+        }
+        if self.max_bytes_per_second.is_some() {
+            options.max_bytes_per_second = self.max_bytes_per_second;
+        }
+        if self.max_files_per_second.is_some() {
+            options.max_files_per_second = self.max_files_per_second;
+        }
+        if let Some(detect_moves) = self.detect_moves {
+            options.move_detection = detect_moves;
+        }
+        if self.delta_min_size.is_some() {
+            options.delta_min_size = self.delta_min_size;
+        }
+        if let Some(special_files) = self.special_files {
+            options.special_files = special_files;
+        }
+        options.one_file_system |= self.one_file_system;
+        if let Some(mtime_tolerance) = self.mtime_tolerance {
+            options.mtime_tolerance = mtime_tolerance;
+        }
+        if let Some(case_sensitivity) = self.case_sensitivity {
+            options.case_sensitivity = case_sensitivity;
+        }
+        options.normalize_unicode |= self.normalize_unicode;
+        if let Some(Margin(margin)) = self.free_space_margin {
+            options.free_space_margin = margin;
+        }
+        if let Some(format) = self.format {
+            options.output_format = format;
+        }
+        let count = |flags: u8| i8::try_from(flags).unwrap_or(i8::MAX);
+        options.verbosity = options.verbosity.saturating_add(count(self.verbose)).saturating_sub(count(self.quiet));
+        options.log_file |= self.log_file;
+        options.excludes.add_all(&self.exclude);
+        if let Some(comparison) = self.comparison {
+            options.comparison = comparison;
+        }
+        if let Some(Retention(retention)) = self.retention {
+            options.retention = retention;
+        }
+        if let Some(debounce) = self.debounce {
+            options.debounce = debounce;
+        }
+        if let Some(Interval(interval)) = self.reconcile_interval {
+            options.reconcile_interval = interval;
+        }
+        for (hook, command) in [(Hook::PreScan, &self.pre_scan_hook), (Hook::PostScan, &self.post_scan_hook),
+                                (Hook::PreApply, &self.pre_apply_hook), (Hook::PostApply, &self.post_apply_hook)] {
+            if let Some(command) = command {
+                options.hooks.set(hook, command);
+            }
+        }
+    }
+}
+
+/// What a run does with the differences it finds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Analyze,
+    Sync,
+    /// Sync, after showing the differences and asking.
+    Interactive,
+    Verify,
+    Ui,
+    /// The ui in the terminal.
+    Tui,
+    Watch,
+}
+
+impl Mode {
+    pub(crate) fn parse(name: &str, value: &str) -> Result<Mode, String> {
+        match value {
+            "analyze" => Ok(Mode::Analyze),
+            "sync" => Ok(Mode::Sync),
+            "interactive" => Ok(Mode::Interactive),
+            "verify" => Ok(Mode::Verify),
+            "ui" => Ok(Mode::Ui),
+            "tui" => Ok(Mode::Tui),
+            "watch" => Ok(Mode::Watch),
+            _ => Err(format!("invalid value for {name}: \"{value}\" (expected sync, interactive, analyze, verify, ui, tui or watch)")),
+        }
+    }
+
+    /// Whether runs of the mode may change the backup directory, analyze and verify only read it.
+    pub(crate) fn writes(self) -> bool {
+        !matches!(self, Mode::Analyze | Mode::Verify)
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Mode::Analyze => "analyze",
+            Mode::Sync => "sync",
+            Mode::Interactive => "interactive",
+            Mode::Verify => "verify",
+            Mode::Ui => "ui",
+            Mode::Tui => "tui",
+            Mode::Watch => "watch",
+        }
+    }
+}
+
+impl Command {
+    /// Mode, source, target and options of the commands that synchronize given directories.
+    pub(crate) fn run_args(&self) -> Option<(Mode, &RunArgs)> {
+        match self {
+            Command::Analyze(run) => Some((Mode::Analyze, run)),
+            Command::Sync { run, interactive: false } => Some((Mode::Sync, run)),
+            Command::Sync { run, interactive: true } => Some((Mode::Interactive, run)),
+            Command::Ui(run) => Some((Mode::Ui, run)),
+            Command::Tui(run) => Some((Mode::Tui, run)),
+            Command::Verify(run) => Some((Mode::Verify, run)),
+            Command::Watch(run) => Some((Mode::Watch, run)),
+            Command::Run { .. } | Command::Daemon { .. } | Command::Completions { .. } => None,
+        }
+    }
+}
+
+fn command() -> clap::Command {
+    let config_path = config::default_config_path().map(|p| p.display().to_string()).unwrap_or_default();
+    Cli::command().after_help(format!(
+        "Options can also be set in the config file (\"{config_path}\"), e.g. \"max_bytes_per_second = '10M'\",\n\
+         and as named profiles with source, target and mode ([profiles.NAME]), which \"run NAME\" starts.\n\
+         The original form \"SOURCE TARGET ui|cmd|just-do-it [OPTIONS]\" is still supported.\n\n\
+         Exit codes: 0 all differences applied, 1 run failed (e.g. backup directory locked or too full), 2 invalid usage, 3 no differences,\n\
+         4 some operations failed, 5 problems found and not applied, 6 aborted by the user\n\n\
+         Hooks: the hook commands get the context of the run in environment variables: DIRSYNC_HOOK, DIRSYNC_MODE, DIRSYNC_SOURCE and\n\
+         DIRSYNC_TARGET, from post-scan on DIRSYNC_DIFFERENCES and DIRSYNC_PROBLEMS, for post-apply also DIRSYNC_OUTCOME, DIRSYNC_EXIT_CODE,\n\
+         DIRSYNC_OPERATIONS, DIRSYNC_FAILED, DIRSYNC_BYTES_WRITTEN and DIRSYNC_SUMMARY. In the uis each analysis and each application runs\n\
+         the hooks, in the watch mode each comparison of the full trees and each application of changes.\n\
+         They can also be set in the config file and profiles, e.g. \"pre_scan_hook = 'systemctl stop postgresql'\"."
+    ))
+}
+
+/// Parses the command line, errors include the requests for help and version (see clap::Error::exit).
+pub(crate) fn try_parse(args: impl IntoIterator<Item = impl Into<String>>) -> Result<Cli, clap::Error> {
+    let args = args.into_iter().map(Into::into).collect();
+    Cli::from_arg_matches(&command().try_get_matches_from(rewrite_legacy_args(args))?)
+}
+
+/// Rewrites "SOURCE TARGET ui|cmd|just-do-it [OPTIONS]" to the corresponding subcommand.
+fn rewrite_legacy_args(args: Vec<String>) -> Vec<String> {
+    if args.len() < 4 || Cli::command().find_subcommand(&args[1]).is_some() {
+        return args;
+    }
+    let subcommand: &[&str] = match args[3].as_str() {
+        "ui" => &["ui"],
+        "cmd" => &["sync", "--interactive"],
+        "just-do-it" => &["sync"],
+        _ => return args,
+    };
+    let mut rewritten = vec![args[0].clone()];
+    rewritten.extend(subcommand.iter().map(|s| s.to_string()));
+    rewritten.extend([args[1].clone(), args[2].clone()]);
+    rewritten.extend(args.into_iter().skip(4));
+    rewritten
+}
+
+pub(crate) fn write_completions(shell: Shell) {
+    let mut command = command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+}
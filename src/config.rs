@@ -0,0 +1,225 @@
+use std::{env, fs};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use serde::Deserialize;
+use crate::cli::Mode;
+use crate::daemon::Schedule;
+use crate::hooks::Hook;
+use crate::options::{CaseSensitivity, Comparison, MoveDetection, MtimeTolerance, OutputFormat, parse_duration, parse_optional_duration, parse_optional_size, parse_size, SpecialFilePolicy, SyncOptions};
+
+/// Contents of the optional config file, e.g.:
+/// ```toml
+/// workers = 4
+/// max_bytes_per_second = "10M"
+/// max_files_per_second = 200
+/// detect_moves = "hash"
+/// delta_min_size = "64M"
+/// special_files = "recreate"
+/// one_file_system = true
+/// mtime_tolerance = "auto"
+/// case_sensitivity = "insensitive"
+/// normalize_unicode = true
+/// free_space_margin = "1G"
+/// format = "json"
+/// log_file = true
+/// excludes = ["*.tmp", ".cache/"]
+/// comparison = "checksum"
+/// retention = 5
+/// debounce = "2s"
+/// reconcile_interval = "1h"
+/// pre_scan_hook = "systemctl stop postgresql"
+/// post_scan_hook = "echo $DIRSYNC_DIFFERENCES differences"
+/// pre_apply_hook = "sync"
+/// post_apply_hook = "systemctl start postgresql"
+/// ```
+/// Every value is optional, options given on the command line take precedence.
+/// Excludes of the config file, a profile and the command line are combined, an empty hook disables the one of the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConfigFile {
+    workers: Option<usize>,
+    max_bytes_per_second: Option<String>,
+    max_files_per_second: Option<f64>,
+    detect_moves: Option<String>,
+    delta_min_size: Option<String>,
+    special_files: Option<String>,
+    one_file_system: Option<bool>,
+    mtime_tolerance: Option<String>,
+    case_sensitivity: Option<String>,
+    normalize_unicode: Option<bool>,
+    free_space_margin: Option<String>,
+    format: Option<String>,
+    log_file: Option<bool>,
+    excludes: Option<Vec<String>>,
+    comparison: Option<String>,
+    /// 0 disables the retention.
+    retention: Option<usize>,
+    debounce: Option<String>,
+    reconcile_interval: Option<String>,
+    pre_scan_hook: Option<String>,
+    post_scan_hook: Option<String>,
+    pre_apply_hook: Option<String>,
+    post_apply_hook: Option<String>,
+    #[serde(default)]
+    pub(crate) profiles: BTreeMap<String, Profile>,
+}
+
+/// A named source with its target(s) and own options, run with "run NAME", e.g.:
+/// ```toml
+/// [profiles.photos]
+/// source = "~/Pictures"
+/// target = "/mnt/backup/Pictures"
+/// mode = "sync"
+/// excludes = [".thumbnails/"]
+/// comparison = "checksum"
+/// retention = 10
+/// ```
+/// Instead of target, several backup directories can be given as targets = ["/mnt/backup/Pictures", "/mnt/nas/Pictures"].
+/// Mode is one of sync (the default), interactive, analyze, verify, ui, tui and watch.
+/// The daemon runs profiles with interval = "6h" (counted from the start of the previous run)
+/// or cron = "30 3 * * Mon-Fri" (minute hour day-of-month month day-of-week, local time), those have to sync.
+/// All options of the config file can be given and override the global ones.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "toml::Table")]
+pub(crate) struct Profile {
+    pub(crate) source: String,
+    pub(crate) targets: Vec<String>,
+    pub(crate) mode: Mode,
+    /// When the daemon runs the profile, None if it does not.
+    pub(crate) schedule: Option<Schedule>,
+    pub(crate) options: ConfigFile,
+}
+
+impl TryFrom<toml::Table> for Profile {
+    type Error = String;
+
+    fn try_from(mut table: toml::Table) -> Result<Profile, String> {
+        let mut take = |key: &str| match table.remove(key) {
+            None => Ok(None),
+            Some(toml::Value::String(value)) => Ok(Some(value)),
+            Some(value) => Err(format!("invalid value for {key}: {value} (expected a string)")),
+        };
+        let source = take("source")?.ok_or("missing source")?;
+        let target = take("target")?;
+        let mode = take("mode")?.map(|mode| Mode::parse("mode", &mode)).transpose()?.unwrap_or(Mode::Sync);
+        let schedule = match (take("interval")?, take("cron")?) {
+            (Some(interval), None) => match parse_duration("interval", &interval)? {
+                interval if interval.is_zero() => return Err("invalid value for interval: 0".to_string()),
+                interval => Some(Schedule::Interval(interval)),
+            },
+            (None, Some(cron)) => Some(Schedule::parse_cron("cron", &cron)?),
+            (Some(_), Some(_)) => return Err("either interval or cron can be given".to_string()),
+            (None, None) => None,
+        };
+        let targets = match (target, table.remove("targets")) {
+            (Some(target), None) => vec![target],
+            (None, Some(targets)) => targets.try_into().map_err(|e: toml::de::Error| format!("invalid value for targets: {}", e.message()))?,
+            (Some(_), Some(_)) => return Err("either target or targets can be given".to_string()),
+            (None, None) => return Err("missing target".to_string()),
+        };
+        if targets.is_empty() {
+            return Err("missing target".to_string());
+        }
+        let options: ConfigFile = table.try_into().map_err(|e: toml::de::Error| e.message().to_string())?;
+        if !options.profiles.is_empty() {
+            return Err("profiles cannot contain profiles".to_string());
+        }
+        Ok(Profile { source: expand_home(&source), targets: targets.iter().map(|t| expand_home(t)).collect(), mode, schedule, options })
+    }
+}
+
+/// Replaces a leading "~/" with the home directory.
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{rest}", home.trim_end_matches('/')),
+        _ => path.to_string(),
+    }
+}
+
+impl ConfigFile {
+    pub(crate) fn apply_to(&self, options: &mut SyncOptions) -> Result<(), String> {
+        if let Some(workers) = self.workers {
+            options.workers = workers.max(1);
+        }
+        if let Some(max_bytes_per_second) = &self.max_bytes_per_second {
+            options.max_bytes_per_second = Some(parse_size("max_bytes_per_second", max_bytes_per_second)?);
+        }
+        if self.max_files_per_second.is_some() {
+            options.max_files_per_second = self.max_files_per_second;
+        }
+        if let Some(detect_moves) = &self.detect_moves {
+            options.move_detection = MoveDetection::parse("detect_moves", detect_moves)?;
+        }
+        if let Some(delta_min_size) = &self.delta_min_size {
+            options.delta_min_size = Some(parse_size("delta_min_size", delta_min_size)?);
+        }
+        if let Some(special_files) = &self.special_files {
+            options.special_files = SpecialFilePolicy::parse("special_files", special_files)?;
+        }
+        if let Some(one_file_system) = self.one_file_system {
+            options.one_file_system = one_file_system;
+        }
+        if let Some(mtime_tolerance) = &self.mtime_tolerance {
+            options.mtime_tolerance = MtimeTolerance::parse("mtime_tolerance", mtime_tolerance)?;
+        }
+        if let Some(case_sensitivity) = &self.case_sensitivity {
+            options.case_sensitivity = CaseSensitivity::parse("case_sensitivity", case_sensitivity)?;
+        }
+        if let Some(normalize_unicode) = self.normalize_unicode {
+            options.normalize_unicode = normalize_unicode;
+        }
+        if let Some(free_space_margin) = &self.free_space_margin {
+            options.free_space_margin = parse_optional_size("free_space_margin", free_space_margin)?;
+        }
+        if let Some(format) = &self.format {
+            options.output_format = OutputFormat::parse("format", format)?;
+        }
+        if let Some(log_file) = self.log_file {
+            options.log_file = log_file;
+        }
+        if let Some(excludes) = &self.excludes {
+            options.excludes.add_all(excludes);
+        }
+        if let Some(comparison) = &self.comparison {
+            options.comparison = Comparison::parse("comparison", comparison)?;
+        }
+        if let Some(retention) = self.retention {
+            options.retention = Some(retention).filter(|&runs| runs > 0);
+        }
+        if let Some(debounce) = &self.debounce {
+            options.debounce = parse_duration("debounce", debounce)?;
+        }
+        if let Some(reconcile_interval) = &self.reconcile_interval {
+            options.reconcile_interval = parse_optional_duration("reconcile_interval", reconcile_interval)?;
+        }
+        for (hook, command) in [(Hook::PreScan, &self.pre_scan_hook), (Hook::PostScan, &self.post_scan_hook),
+                                (Hook::PreApply, &self.pre_apply_hook), (Hook::PostApply, &self.post_apply_hook)] {
+            if let Some(command) = command {
+                options.hooks.set(hook, command);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// $XDG_CONFIG_HOME/directory_synchronizer/config.toml, falling back to ~/.config/directory_synchronizer/config.toml
+pub(crate) fn default_config_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME").filter(|p| !p.is_empty()).map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("directory_synchronizer").join("config.toml"))
+}
+
+/// Reads the config file at the default location. A missing file is not an error.
+pub(crate) fn load_config() -> Result<ConfigFile, String> {
+    let Some(path) = default_config_path() else { return Ok(ConfigFile::default()) };
+    match fs::read_to_string(&path) {
+        Ok(content) => parse_config(&content).map_err(|e| format!("invalid config file \"{}\": {e}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(ConfigFile::default()),
+        Err(e) => Err(format!("cannot read config file \"{}\": {e}", path.display())),
+    }
+}
+
+pub(crate) fn parse_config(content: &str) -> Result<ConfigFile, String> {
+    toml::from_str(content).map_err(|e| e.to_string())
+}
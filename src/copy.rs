@@ -0,0 +1,142 @@
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Read, Seek, Write};
+use log::debug;
+use crate::throttle::Throttle;
+
+/// How the content of a file was transferred to the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CopyMethod {
+    /// Copy-on-write clone, no data written (btrfs, XFS, ...).
+    Reflink,
+    /// In-kernel copy of the data regions, holes of sparse files are kept.
+    CopyFileRange,
+    /// Only the changed blocks of an existing target file were rewritten.
+    Delta,
+    /// Read and written through user space.
+    Plain,
+}
+
+impl Display for CopyMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::CopyFileRange => "copy_file_range",
+            CopyMethod::Delta => "delta",
+            CopyMethod::Plain => "plain",
+        })
+    }
+}
+
+/// Size of the chunks copied at once, each accounted against the byte rate limit.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Copies the content and permissions of a file, replacing the target if it exists.
+/// Tries a reflink first, then copy_file_range (preserving holes), then a plain copy.
+/// Returns the number of bytes written (0 for a reflink, which shares the data instead of writing it) and the method that was used.
+pub(crate) fn copy_file(from: &str, to: &str, throttle: &Throttle) -> io::Result<(u64, CopyMethod)> {
+    let mut reader = File::open(from)?;
+    let metadata = reader.metadata()?;
+    let mut writer = OpenOptions::new().write(true).create(true).truncate(true).open(to)?;
+    writer.set_permissions(metadata.permissions())?;
+
+    if reflink(&reader, &writer).is_ok() {
+        return Ok((0, CopyMethod::Reflink));
+    }
+    match copy_data_regions(&reader, &writer, metadata.len(), throttle) {
+        Ok(bytes) => return Ok((bytes, CopyMethod::CopyFileRange)),
+        Err(e) if is_unsupported(&e) => {
+            debug!("copy_file_range not supported, copying plainly: {to}\n    {e}");
+            writer.set_len(0)?;
+            reader.rewind()?;
+            writer.rewind()?;
+        }
+        Err(e) => return Err(e),
+    }
+    copy_plain(&mut reader, &mut writer, throttle).map(|bytes| (bytes, CopyMethod::Plain))
+}
+
+fn is_unsupported(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EXDEV) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL))
+        || e.kind() == ErrorKind::Unsupported
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(reader: &File, writer: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: both descriptors are valid for the duration of the call
+    let result = unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_reader: &File, _writer: &File) -> io::Result<()> {
+    Err(io::Error::from(ErrorKind::Unsupported))
+}
+
+/// Copies only the data regions of the source (found with SEEK_DATA/SEEK_HOLE) using copy_file_range,
+/// so that holes of sparse files stay holes in the target.
+#[cfg(target_os = "linux")]
+fn copy_data_regions(reader: &File, writer: &File, len: u64, throttle: &Throttle) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+    let (fd_in, fd_out) = (reader.as_raw_fd(), writer.as_raw_fd());
+    let mut written = 0u64;
+    let mut offset = 0i64;
+    while (offset as u64) < len {
+        // SAFETY: lseek on a valid descriptor
+        let data_start = unsafe { libc::lseek(fd_in, offset, libc::SEEK_DATA) };
+        if data_start < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENXIO) { break } // only a hole remains
+            return Err(e);
+        }
+        // SAFETY: lseek on a valid descriptor
+        let data_end = unsafe { libc::lseek(fd_in, data_start, libc::SEEK_HOLE) };
+        if data_end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut off_in = data_start;
+        let mut off_out = data_start;
+        while off_in < data_end {
+            let chunk = ((data_end - off_in) as usize).min(CHUNK_SIZE);
+            throttle.consume_bytes(chunk);
+            // SAFETY: valid descriptors and offsets that live for the duration of the call
+            let copied = unsafe { libc::copy_file_range(fd_in, &mut off_in, fd_out, &mut off_out, chunk, 0) };
+            if copied < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if copied == 0 {
+                break; // source shrank while copying
+            }
+            written += copied as u64;
+        }
+        offset = data_end;
+    }
+    // extends the target over trailing holes
+    writer.set_len(len)?;
+    Ok(written)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_data_regions(_reader: &File, _writer: &File, _len: u64, _throttle: &Throttle) -> io::Result<u64> {
+    Err(io::Error::from(ErrorKind::Unsupported))
+}
+
+fn copy_plain(reader: &mut File, writer: &mut File, throttle: &Throttle) -> io::Result<u64> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut bytes = 0u64;
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        throttle.consume_bytes(len);
+        writer.write_all(&buf[..len])?;
+        bytes += len as u64;
+    }
+    Ok(bytes)
+}
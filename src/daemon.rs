@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use serde::Serialize;
+use crate::apply::{ApplyReport, OperationCounts};
+use crate::outcome::Outcome;
+use crate::state::state_dir;
+
+/// Directory in the state directory of the target, which keeps the reports of the scheduled runs.
+pub(crate) const REPORTS_DIR_NAME: &str = "reports";
+
+/// Number of reports kept per target, older ones are removed.
+const REPORTS_KEPT: usize = 100;
+
+/// Wait before retrying after the first failed run, doubled with every further failure.
+const FIRST_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// When a profile runs in daemon mode.
+#[derive(Debug, Clone)]
+pub(crate) enum Schedule {
+    /// Every interval, counted from the start of the previous run. The first run starts right away.
+    Interval(Duration),
+    /// At the times of a cron expression (minute hour day-of-month month day-of-week, local time).
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Parses a standard cron expression with five fields, e.g. "30 3 * * Mon-Fri" or "0 */6 * * *".
+    /// Days of the week are 0 (or 7) for Sunday to 6 for Saturday, or their names.
+    pub(crate) fn parse_cron(name: &str, value: &str) -> Result<Schedule, String> {
+        let invalid = |reason: &str| format!("invalid value for {name}: \"{value}\" ({reason})");
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(invalid("expected the five fields minute, hour, day of month, month and day of week"));
+        };
+        //the cron crate has an additional seconds field and counts the days of the week from 1 (Sunday)
+        let expression = format!("0 {minute} {hour} {day} {month} {}", shift_weekdays(weekday));
+        cron::Schedule::from_str(&expression).map(|schedule| Schedule::Cron(Box::new(schedule))).map_err(|e| invalid(&e.to_string()))
+    }
+
+    /// The first time after the given one at which the profile runs, None if there is none.
+    pub(crate) fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Interval(interval) => Some(after + *interval),
+            Schedule::Cron(schedule) => schedule.after(&DateTime::<Local>::from(after)).next().map(SystemTime::from),
+        }
+    }
+
+    /// The time of the first run of a daemon started at the given time.
+    fn first_run(&self, start: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Interval(_) => Some(start),
+            Schedule::Cron(_) => self.next_after(start),
+        }
+    }
+}
+
+/// Replaces the numbers of days of the week (0-7, Sunday is 0 and 7) with those of the cron crate (1-7, Sunday is 1).
+/// Steps ("*/2") are kept, ranges ending on Sunday (7) are split into the days before it and Sunday.
+fn shift_weekdays(field: &str) -> String {
+    field.split(',').map(shift_weekday_item).collect::<Vec<_>>().join(",")
+}
+
+fn shift_weekday_item(item: &str) -> String {
+    let (range, step) = match item.split_once('/') {
+        Some((range, step)) => (range, Some(step)),
+        None => (item, None),
+    };
+    let with_step = |shifted: String| match step {
+        Some(step) => format!("{shifted}/{step}"),
+        None => shifted,
+    };
+    let shift = |day: u32| day % 7 + 1;
+    let parsed = match range.split_once('-') {
+        Some((first, last)) => first.parse::<u32>().ok().zip(last.parse::<u32>().ok()),
+        None => range.parse::<u32>().ok().map(|day| (day, day)),
+    };
+    match parsed.filter(|&(first, last)| first <= last && last <= 7) {
+        //names, "*" and invalid days are left to the cron crate
+        None => item.to_string(),
+        Some((day, _)) if !range.contains('-') => with_step(shift(day).to_string()),
+        Some((0, 7)) => with_step("1-7".to_string()),
+        Some((first, 7)) => match step.map_or(Ok(1), str::parse::<usize>) {
+            //the cron crate has no ranges wrapping around from Saturday to Sunday, so the days are listed
+            Ok(step) if step > 0 => (first..=7).step_by(step).map(|day| shift(day).to_string()).collect::<Vec<_>>().join(","),
+            _ => item.to_string(),
+        },
+        Some((first, last)) => with_step(format!("{}-{}", shift(first), shift(last))),
+    }
+}
+
+/// Source of the current time and of waiting, replaced in tests to verify schedules without waiting.
+pub(crate) trait Clock {
+    fn now(&self) -> SystemTime;
+    fn sleep_until(&self, time: SystemTime);
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, time: SystemTime) {
+        //in steps, so that changes of the system time (e.g. suspend) do not delay runs
+        while let Ok(remaining) = time.duration_since(SystemTime::now()) {
+            if remaining.is_zero() {
+                break;
+            }
+            thread::sleep(remaining.min(Duration::from_secs(60)));
+        }
+    }
+}
+
+/// Decides when which profile runs next.
+///
+/// Profiles run one after the other, never twice at the same time.
+/// Times at which a profile was due while its previous run was still going are skipped, not caught up.
+/// After a failed run, the next one waits at least for a backoff that doubles with each consecutive failure.
+pub(crate) struct Scheduler {
+    jobs: Vec<Job>,
+}
+
+struct Job {
+    name: String,
+    schedule: Schedule,
+    next_run: Option<SystemTime>,
+    failures: u32,
+}
+
+impl Scheduler {
+    pub(crate) fn new(schedules: Vec<(String, Schedule)>, start: SystemTime) -> Scheduler {
+        let jobs = schedules.into_iter()
+            .map(|(name, schedule)| Job { next_run: schedule.first_run(start), name, schedule, failures: 0 })
+            .collect();
+        Scheduler { jobs }
+    }
+
+    /// The profile to run next and when, the first in order of the config file if several are due at the same time.
+    pub(crate) fn next_run(&self) -> Option<(&str, SystemTime)> {
+        self.jobs.iter()
+            .filter_map(|job| job.next_run.map(|at| (job.name.as_str(), at)))
+            .min_by_key(|(_, at)| *at)
+    }
+
+    /// Schedules the run after the one of the profile that started and ended at the given times.
+    pub(crate) fn finished(&mut self, name: &str, started: SystemTime, succeeded: bool, ended: SystemTime) {
+        let Some(job) = self.jobs.iter_mut().find(|job| job.name == name) else { return };
+        let mut next_run = job.schedule.next_after(started);
+        let mut skipped = 0;
+        while let Some(at) = next_run.filter(|&at| at <= ended) {
+            skipped += 1;
+            next_run = job.schedule.next_after(at);
+        }
+        if skipped > 0 {
+            warn!("Skipped {skipped} run(s) of profile \"{name}\", its previous run was still going.");
+        }
+        if succeeded {
+            job.failures = 0;
+        } else {
+            job.failures += 1;
+            let backoff = backoff(job.failures);
+            next_run = next_run.map(|at| at.max(ended + backoff));
+            warn!("Profile \"{name}\" failed {} time(s) in a row, retrying in {} at the earliest.",
+                job.failures, humantime::format_duration(backoff));
+        }
+        job.next_run = next_run;
+    }
+}
+
+/// Wait after the given number of consecutive failures.
+pub(crate) fn backoff(failures: u32) -> Duration {
+    FIRST_BACKOFF.saturating_mul(2u32.saturating_pow(failures.saturating_sub(1))).min(MAX_BACKOFF)
+}
+
+/// Runs the profiles when they are due, until no profile has a further run or the end (if given, for tests) is reached.
+/// The run function returns whether the run succeeded.
+pub(crate) fn run_scheduled(scheduler: &mut Scheduler, clock: &dyn Clock, end: Option<SystemTime>, run: &mut dyn FnMut(&str) -> bool) {
+    loop {
+        let Some((name, at)) = scheduler.next_run() else { return };
+        if end.is_some_and(|end| at > end) {
+            return;
+        }
+        let name = name.to_string();
+        info!("Next run: profile \"{name}\" at {}", humantime::format_rfc3339_seconds(at));
+        clock.sleep_until(at);
+        let started = clock.now();
+        let succeeded = run(&name);
+        scheduler.finished(&name, started, succeeded, clock.now());
+    }
+}
+
+/// Result of a scheduled run of a profile on one of its targets, written as json to the state directory of the target.
+#[derive(Debug, Serialize)]
+pub(crate) struct RunReport<'a> {
+    pub(crate) profile: &'a str,
+    pub(crate) source: &'a str,
+    pub(crate) target: &'a str,
+    pub(crate) started: String,
+    pub(crate) finished: String,
+    pub(crate) outcome: &'static str,
+    pub(crate) exit_code: i32,
+    /// Why the run could not be started or completed.
+    pub(crate) error: Option<String>,
+    pub(crate) operations: usize,
+    pub(crate) failed: usize,
+    pub(crate) bytes_written: u64,
+    pub(crate) by_operation: BTreeMap<&'static str, OperationCounts>,
+}
+
+impl<'a> RunReport<'a> {
+    pub(crate) fn new(profile: &'a str, source: &'a str, target: &'a str, run_time: Range<SystemTime>, report: &ApplyReport, outcome: Outcome, error: Option<String>) -> RunReport<'a> {
+        RunReport {
+            profile,
+            source,
+            target,
+            started: humantime::format_rfc3339_seconds(run_time.start).to_string(),
+            finished: humantime::format_rfc3339_seconds(run_time.end).to_string(),
+            outcome: outcome.description(),
+            exit_code: outcome.exit_code(),
+            error,
+            operations: report.results.len(),
+            failed: report.failed(),
+            bytes_written: report.bytes_written(),
+            by_operation: report.by_operation(),
+        }
+    }
+
+    /// Writes the report to ".directory_synchronizer/reports/<started>.json" in the target and removes the oldest reports.
+    /// Fails if the target does not exist, it is not created for the report.
+    pub(crate) fn write(&self) -> io::Result<PathBuf> {
+        if !fs::metadata(self.target)?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotADirectory, "not a directory"));
+        }
+        let dir = reports_dir(self.target);
+        fs::create_dir_all(&dir)?;
+        //without colons, like the versions
+        let path = dir.join(format!("{}-{}.json", self.started.replace(':', ""), self.profile));
+        fs::write(&path, serde_json::to_string_pretty(self).expect("reports are always serializable"))?;
+        let mut reports: Vec<PathBuf> = fs::read_dir(&dir)?.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+        reports.sort();
+        for outdated in &reports[..reports.len().saturating_sub(REPORTS_KEPT)] {
+            fs::remove_file(outdated)?;
+        }
+        Ok(path)
+    }
+}
+
+pub(crate) fn reports_dir(target_dir: &str) -> PathBuf {
+    state_dir(target_dir).join(REPORTS_DIR_NAME)
+}
@@ -0,0 +1,60 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use log::debug;
+use crate::throttle::Throttle;
+
+/// Size of the blocks that are compared and, if different, rewritten.
+pub(crate) const DELTA_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Updates an existing target file in place, so that it equals the source file.
+/// Both files are compared block by block and only blocks that differ are rewritten,
+/// which is much cheaper than a full copy for large files of which only few blocks changed
+/// (vm images, database dumps).
+///
+/// Returns the number of bytes written.
+/// If this fails halfway, the target is left partially updated, so callers should fall back to a full copy.
+pub(crate) fn update_changed_blocks(from: &str, to: &str, throttle: &Throttle) -> io::Result<u64> {
+    let mut reader = File::open(from)?;
+    let mut target = OpenOptions::new().read(true).write(true).open(to)?;
+    let source_metadata = reader.metadata()?;
+
+    let mut source_block = vec![0u8; DELTA_BLOCK_SIZE];
+    let mut target_block = vec![0u8; DELTA_BLOCK_SIZE];
+    let mut offset = 0u64;
+    let mut written = 0u64;
+    loop {
+        let len = read_block(&mut reader, &mut source_block)?;
+        if len == 0 {
+            break;
+        }
+        let target_len = read_block(&mut target, &mut target_block[..len])?;
+        if target_len != len || source_block[..len] != target_block[..len] {
+            throttle.consume_bytes(len);
+            target.seek(SeekFrom::Start(offset))?;
+            target.write_all(&source_block[..len])?;
+            debug!("Rewrote {len} changed bytes at offset {offset}: {to}");
+            written += len as u64;
+        }
+        offset += len as u64;
+    }
+
+    target.set_len(source_metadata.len())?;
+    target.set_permissions(source_metadata.permissions())?;
+    debug!("Rewrote {written} of {} bytes: {to}", source_metadata.len());
+    Ok(written)
+}
+
+/// Reads until the buffer is full or the end of the file is reached.
+fn read_block(reader: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use chrono::{DateTime, Local};
+use crate::differences::{AnnotatedPath, Difference};
+
+const CHOICES: &str = "[y] apply, [n] skip, [a] apply all remaining, [s] skip all remaining, [d] details, [q] quit";
+const PROBLEM_CHOICES: &str = "[t] take source, [k] keep backup, [a] apply all remaining, [s] skip all remaining, [d] details, [q] quit";
+
+/// An answer for a single difference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Choice {
+    Apply,
+    Skip,
+    ApplyRemaining,
+    SkipRemaining,
+    Details,
+    Quit,
+}
+
+impl Choice {
+    /// Differences with problems are not applied or skipped, but taken from the source or kept in the backup,
+    /// so that they are decided on deliberately.
+    fn parse(answer: &str, problem: bool) -> Option<Choice> {
+        match (answer, problem) {
+            ("y", false) | ("t", true) => Some(Choice::Apply),
+            ("n", false) | ("k", true) => Some(Choice::Skip),
+            ("a", _) => Some(Choice::ApplyRemaining),
+            ("s", _) => Some(Choice::SkipRemaining),
+            ("d", _) => Some(Choice::Details),
+            ("q", _) => Some(Choice::Quit),
+            _ => None,
+        }
+    }
+}
+
+/// Asks for each difference whether it is applied and returns the answers in the order of the differences.
+/// After "apply all remaining", differences with problems are still asked for, after "skip all remaining" none is.
+/// Returns None if the user quit or the input ended, then nothing is applied.
+pub(crate) fn decide_each(diffs: &[Difference], problems: &HashMap<Difference, String>, source_path: &str, target_path: &str,
+                          input: &mut dyn BufRead, output: &mut dyn Write) -> Option<Vec<bool>> {
+    let mut selected = Vec::with_capacity(diffs.len());
+    //the answer for all remaining differences, once given
+    let mut remaining = None;
+    for (i, d) in diffs.iter().enumerate() {
+        let problem = problems.get(d);
+        match (remaining, problem) {
+            (Some(false), _) | (Some(true), None) => {
+                selected.push(remaining == Some(true));
+                continue;
+            }
+            _ => {}
+        }
+        writeln!(output, "\n[{}/{}] {}\n    in directory: {}", i + 1, diffs.len(), d.describe(), d.get_directory_path(source_path.len(), target_path.len())).ok();
+        if let Some(problem) = problem {
+            writeln!(output, "    Problem: {problem}").ok();
+        }
+        loop {
+            write!(output, "{}? ", if problem.is_some() { PROBLEM_CHOICES } else { CHOICES }).ok();
+            output.flush().ok();
+            let mut answer = String::new();
+            if input.read_line(&mut answer).unwrap_or(0) == 0 {
+                return None;
+            }
+            match Choice::parse(answer.trim(), problem.is_some()) {
+                Some(Choice::Apply) => selected.push(true),
+                Some(Choice::Skip) => selected.push(false),
+                Some(Choice::ApplyRemaining) => {
+                    remaining = Some(true);
+                    selected.push(true);
+                }
+                Some(Choice::SkipRemaining) => {
+                    remaining = Some(false);
+                    selected.push(false);
+                }
+                Some(Choice::Details) => {
+                    writeln!(output, "{}", details(d)).ok();
+                    continue;
+                }
+                Some(Choice::Quit) => return None,
+                None => {
+                    writeln!(output, "Please answer with one of the letters in brackets.").ok();
+                    continue;
+                }
+            }
+            break;
+        }
+    }
+    Some(selected)
+}
+
+/// Both sides of the difference with their sizes and modification times.
+pub(crate) fn details(d: &Difference) -> String {
+    let mut details = format!("    source: {}\n    backup: {}", describe_side(d.p_source.as_ref()), describe_side(d.p_target.as_ref()));
+    if let Some(moved_from) = &d.p_moved_from {
+        details += &format!("\n    moved from (in backup): {}", describe_side(Some(moved_from)));
+    }
+    if let Some(kept) = &d.collides_with {
+        details += &format!("\n    collides with (in backup): {}", describe_side(Some(kept)));
+    }
+    details
+}
+
+fn describe_side(path: Option<&AnnotatedPath>) -> String {
+    match path {
+        None => "not present".to_string(),
+        Some(path) if path.is_dir() => format!("\"{}\" (directory)", path.path),
+        Some(path) => format!("\"{}\" ({}, {} bytes, modified {})", path.path, path.type_marker(), path.len(),
+                              DateTime::<Local>::from(path.modified()).format("%Y-%m-%d %H:%M:%S%.3f")),
+    }
+}
@@ -1,137 +1,216 @@
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::{fs, io};
 use std::hash::{Hash, Hasher};
-use std::io::ErrorKind;
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-use filetime::{FileTime, set_file_mtime};
-
-pub(crate) fn apply_diffs_source_to_target_with_prints<'a, I>(source_base_path: &str, target_base_path: &str, diffs: I) where I: Iterator<Item= &'a Difference>+Clone {
-    let mut to_buf = PathBuf::new();
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use log::{error, info, warn};
+use crate::apply::{ApplyContext, ApplyReport, execute_plan, Operation};
+use crate::hardlinks::{HardlinkChange, link_group, LinkGroup, LinkTracker};
+use crate::filters::Excludes;
+use crate::options::{Comparison, MoveDetection, SpecialFilePolicy, SyncOptions};
+use crate::space::check_free_space;
+use crate::special::{special_kind, SpecialKind};
+use crate::state::STATE_DIR_NAME;
+use crate::names::NameMatching;
+use crate::output::observer;
+use crate::progress::Progress;
+use crate::timestamps::{is_newer, mtimes_equal};
+use crate::versions;
+use twox_hash::XxHash64;
+
+pub(crate) fn apply_diffs_source_to_target_with_prints<'a, I>(source_base_path: &str, target_base_path: &str, diffs: I, options: &SyncOptions) -> ApplyReport where I: Iterator<Item= &'a Difference>+Clone {
+    let mut plan = Vec::new();
     for d in diffs {
-        apply_diff(&source_base_path, target_base_path, d.p_source.as_ref(), d.p_target.as_ref(), &mut to_buf);
+        plan_diff(source_base_path, target_base_path, d, options, &mut plan);
     }
+    let report = execute_plan(plan, &ApplyContext::new(options, target_base_path), observer(options));
+    prune_versions(target_base_path, options);
+    report
 }
 
-pub(crate) fn apply_during_analysis_with_prints(source_base_path: &str, target_base_path: &str) {
-    let mut to_buf = PathBuf::new();
-    find_differences_rec(
+/// Applies differences as soon as they are found.
+/// Fails before changing anything, if the free space check is enabled and the target is too small.
+pub(crate) fn apply_during_analysis_with_prints(source_base_path: &str, target_base_path: &str, options: &SyncOptions, source_listing: &SourceListing) -> Result<ApplyReport, String> {
+    if options.move_detection != MoveDetection::Off || options.free_space_margin.is_some() {
+        //moves can only be paired and the required space only be known once all differences are known
+        return apply_after_analysis_with_prints(source_base_path, target_base_path, options, source_listing, &mut |_, _| Ok(()));
+    }
+
+    let context = ApplyContext::new(options, target_base_path);
+    let mut report = ApplyReport::default();
+    let mut plan = Vec::new();
+    let mut scanner = Scanner::new(source_base_path, target_base_path, options, source_listing);
+    scanner.find_differences_rec(
         source_base_path, target_base_path,
-        &mut |diff_s, diff_t|
-            apply_diff(source_base_path, target_base_path, diff_s, diff_t, &mut to_buf)
-    )
-}
-
-fn apply_diff(source_base_path: &str, target_base_path: &str, diff_s: Option<&AnnotatedPath>, diff_t: Option<&AnnotatedPath>, to_buf: &mut PathBuf) {
-    if diff_s.is_some() && diff_t.is_some() {
-        let psu = diff_s.unwrap();
-        let from = &psu.path;
-        let to = &diff_t.as_ref().unwrap().path;
-        println!("Replacing file/directory...:\n    '{from}' -> {to}");
-        let err = copy_file_or_dir_with_prints(psu, &from, &to);
-        match err {
-            Ok(len) => println!("Successfully replaced file/directory: \n    '{from}' -> {to}\n    {len} bytes written"),
-            Err(e) => println!("Error replacing file/directory: \n    '{from}' -> {to}\n    {e}")
-        }
-    } else if diff_s.is_some() && diff_t.is_none() {
-        let psu = diff_s.unwrap();
-        let from = &psu.path;
-        to_buf.clear();
-        to_buf.push(target_base_path);
-        if &from[source_base_path.len()..source_base_path.len()+1] == "/" {
-            to_buf.push(&from[source_base_path.len() + 1..]);
-        } else {
-            to_buf.push(&from[source_base_path.len()..]);
-        }
-        println!("Copying file/directory...:\n    '{from}' -> {}", to_buf.to_str().unwrap());
-        let err = copy_file_or_dir_with_prints(psu, &from, &to_buf.to_str().unwrap());
-        match err {
-            Ok(len) => println!("Successfully copied file/directory: \n    '{from}' -> {}\n    {len} bytes written", to_buf.to_str().unwrap()),
-            Err(e) => println!("Error copied file/directory: \n    '{from}' -> {}\n    {e}", to_buf.to_str().unwrap())
-        }
-    } else if diff_s.is_none() && diff_t.is_some() {
-        let ptu = diff_t.unwrap();
-        let pt_path = &ptu.path;
-        let err = if ptu.is_dir() {
-            println!("Removing directory...: '{pt_path}'");
-            fs::remove_dir_all(&pt_path)
-        } else {
-            println!("Removing file...: '{pt_path}'");
-            fs::remove_file(&pt_path)
-        };
-        match err {
-            Ok(_) => println!("Successfully removed file/directory: ’{pt_path}’"),
-            Err(e) => println!("Error removing file/directory: ’{pt_path}’\n    {e}")
+        &mut |d| {
+            observer(options).difference_found(&d, None);
+            plan_diff(source_base_path, target_base_path, &d, options, &mut plan);
+            report.merge(execute_plan(std::mem::take(&mut plan), &context, observer(options)));
         }
+    );
+
+    //files to be linked to files that were copied above are already linked while copying
+    let mut link_differences = Vec::new();
+    scanner.finish(&mut link_differences);
+    for d in &link_differences {
+        observer(options).difference_found(d, None);
+        plan_diff(source_base_path, target_base_path, d, options, &mut plan);
     }
+    report.merge(execute_plan(plan, &context, observer(options)));
+    prune_versions(target_base_path, options);
+    Ok(report)
 }
 
-fn copy_file_or_dir_with_prints(psu: &AnnotatedPath, from: &str, to: &str)-> io::Result<u64> {
-    return if psu.is_dir() {
-        let mut byte_counter = 0u64;
-        match fs::create_dir(&to) {
-            Ok(_) => {}
-            Err(e) => {
-                match e.kind() {
-                    ErrorKind::AlreadyExists => {}
-                    _ => {return Err(e);}
-                }
-            }
-        }
+/// Finds all differences first and applies them afterwards, if `before_apply` (called with the number of differences and problems) succeeds.
+/// Fails before changing anything, if the free space check is enabled and the target is too small.
+pub(crate) fn apply_after_analysis_with_prints(source_base_path: &str, target_base_path: &str, options: &SyncOptions, source_listing: &SourceListing,
+                                               before_apply: &mut dyn FnMut(usize, usize) -> Result<(), String>) -> Result<ApplyReport, String> {
+    let diffs = find_differences_with(source_listing, source_base_path, target_base_path, options);
+    check_free_space(target_base_path, diffs.iter(), options)?;
+    let problems = verify_source_fully_newer_than_target(&diffs, options);
+    for d in &diffs {
+        observer(options).difference_found(d, problems.get(d).map(String::as_str));
+    }
+    before_apply(diffs.len(), problems.len())?;
+    Ok(apply_diffs_source_to_target_with_prints(source_base_path, target_base_path, diffs.iter(), options))
+}
 
-        let mut target_path = PathBuf::new();
-        for entry in walkdir::WalkDir::new(&from)
-            .min_depth(1)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            target_path.clear();
-            target_path.push(to);
-            target_path.push(entry.path().strip_prefix(from).unwrap());
+/// Removes the versions of runs beyond the retention.
+fn prune_versions(target_base_path: &str, options: &SyncOptions) {
+    let Some(keep) = options.retention else { return };
+    match versions::prune_versions(target_base_path, keep) {
+        Ok(removed) => removed.iter().for_each(|run| info!("Removed outdated versions: {}", run.display())),
+        Err(e) => warn!("Warning, could not remove outdated versions: {e}"),
+    }
+}
 
-            let source_metadata = match entry.metadata() {
-                Ok(md) => {md}
-                Err(e) => { return Err(io::Error::from(e)); }
-            };
+/// Breaks a difference down into the operations that make the target equal to the source.
+fn plan_diff(source_base_path: &str, target_base_path: &str, d: &Difference, options: &SyncOptions, plan: &mut Vec<Operation>) {
+    if d.collides_with.is_some() {
+        warn!("Warning, skipping: {}", d.describe());
+        return;
+    }
+    if let (Some(psu), Some(HardlinkChange::Link { to: link_to })) = (&d.p_source, &d.hardlink) {
+        plan.push(Operation::HardLink {
+            existing: target_path_for(source_base_path, target_base_path, &link_to.path),
+            to: d.p_target.as_ref().map(|pt| pt.path.clone()).unwrap_or_else(|| target_path_for(source_base_path, target_base_path, &psu.path)),
+            replace: d.p_target.is_some(),
+        });
+        return;
+    }
 
-            if source_metadata.is_dir() {
-                match fs::create_dir(&target_path) {
-                    Ok(_) => {}
-                    Err(e) => { return Err(e); }
-                };
-                match fs::set_permissions(&target_path, source_metadata.permissions()) {
-                    Ok(_) => {}
-                    Err(e) => { return Err(e); }
+    match (&d.p_source, &d.p_target) {
+        (Some(psu), None) if d.p_moved_from.is_some() => {
+            let from = d.p_moved_from.as_ref().unwrap().path.clone();
+            plan.push(Operation::MoveFile { from, to: target_path_for(source_base_path, target_base_path, &psu.path) });
+        }
+        (Some(psu), Some(ptu)) => {
+            plan_copy(psu, &ptu.path, true, options, plan);
+        }
+        (Some(psu), None) => {
+            plan_copy(psu, &target_path_for(source_base_path, target_base_path, &psu.path), false, options, plan);
+        }
+        (None, Some(ptu)) => {
+            if ptu.is_dir() {
+                let root_device = walk_root_device(&ptu.path, options);
+                let mut skipped_mount_points = Vec::new();
+                for entry in walkdir::WalkDir::new(&ptu.path)
+                    .contents_first(true)
+                    .same_file_system(options.one_file_system)
+                    .into_iter()
+                    .filter_map(|e| e.ok())
+                {
+                    let path = entry.path().to_str().unwrap().to_string();
+                    if is_mount_point(&entry, root_device) {
+                        skipped_mount_points.push(path);
+                    } else if entry.file_type().is_dir() {
+                        //directories containing a mount point are kept, together with the files mounted below them
+                        if !skipped_mount_points.iter().any(|m| Path::new(m).starts_with(entry.path())) {
+                            plan.push(Operation::RemoveDir { path });
+                        }
+                    } else {
+                        plan.push(Operation::RemoveFile { path });
+                    }
                 }
+                warn_skipped_mount_points(&skipped_mount_points);
             } else {
-                let source_modified = match source_metadata.modified() {
-                    Ok(m) => { m }
-                    Err(e) => { return Err(e); }
-                };
-
-                match copy_file_update_time(source_modified, entry.path(), &target_path) {
-                    Ok(bytes) => { byte_counter += bytes; }
-                    Err(e) => { return Err(e); }
-                };
+                plan.push(Operation::RemoveFile { path: ptu.path.clone() });
             }
         }
-        Ok(byte_counter)
+        (None, None) => {}
+    }
+}
+
+fn plan_special(from: &str, to: &str, kind: SpecialKind, replace: bool, options: &SyncOptions, plan: &mut Vec<Operation>) {
+    match options.special_files {
+        SpecialFilePolicy::Skip => warn!("Warning, skipping special file: {}['{from}']", kind.marker()),
+        SpecialFilePolicy::Recreate => plan.push(Operation::CreateSpecial { from: from.to_string(), to: to.to_string(), modified: fs::symlink_metadata(from).and_then(|m| m.modified()).ok(), replace }),
+        SpecialFilePolicy::Error => plan.push(Operation::RefuseSpecial { path: from.to_string(), marker: kind.marker() }),
+    }
+}
+
+/// Path in the target directory that corresponds to the given path in the source directory.
+fn target_path_for(source_base_path: &str, target_base_path: &str, source_path: &str) -> String {
+    let mut to_buf = PathBuf::from(target_base_path);
+    if &source_path[source_base_path.len()..source_base_path.len()+1] == "/" {
+        to_buf.push(&source_path[source_base_path.len() + 1..]);
     } else {
-        return copy_file_update_time(psu.modified(), from, to);
+        to_buf.push(&source_path[source_base_path.len()..]);
     }
+    to_buf.to_str().unwrap().to_string()
 }
-fn copy_file_update_time<P: AsRef<Path>, Q: AsRef<Path>>(from_modified: SystemTime, from: P, to: Q) -> io::Result<u64> {
-    return match fs::copy(&from, &to) {
-        Ok(bytes) => {
-            match set_file_mtime(&to, FileTime::from(from_modified)) {
-                Ok(_) => {}
-                Err(e) => { return Err(e); }
-            }
-            Ok(bytes)
+
+fn plan_copy(psu: &AnnotatedPath, to: &str, replace: bool, options: &SyncOptions, plan: &mut Vec<Operation>) {
+    if let Some(kind) = psu.special() {
+        plan_special(&psu.path, to, kind, replace, options, plan);
+        return;
+    }
+    if !psu.is_dir() {
+        plan.push(Operation::CopyFile { from: psu.path.clone(), to: to.to_string(), modified: psu.modified(), replace, link_group: psu.link_group() });
+        return;
+    }
+
+    plan.push(Operation::CreateDir { from: psu.path.clone(), to: to.to_string() });
+    let mut target_path = PathBuf::new();
+    let source_root_len = psu.path.len() - psu.relative_path().len();
+    let root_device = walk_root_device(&psu.path, options);
+    let mut skipped_mount_points = Vec::new();
+    for entry in walkdir::WalkDir::new(&psu.path)
+        .min_depth(1)
+        .same_file_system(options.one_file_system)
+        .into_iter()
+        .filter_entry(|e| !options.excludes.is_excluded(&e.path().to_string_lossy()[source_root_len..], e.file_type().is_dir()))
+        .filter_map(|e| e.ok())
+    {
+        if is_mount_point(&entry, root_device) {
+            skipped_mount_points.push(entry.path().to_string_lossy().into_owned());
+            continue;
         }
-        Err(e) => { Err(e) }
-    };
+        target_path.clear();
+        target_path.push(to);
+        target_path.push(entry.path().strip_prefix(&psu.path).unwrap());
+
+        let from = entry.path().to_str().unwrap().to_string();
+        let to = target_path.to_str().unwrap().to_string();
+        match entry.metadata() {
+            Ok(md) if md.is_dir() => plan.push(Operation::CreateDir { from, to }),
+            Ok(md) => if let Some(kind) = special_kind(&md) {
+                plan_special(&from, &to, kind, false, options, plan)
+            } else {
+                match md.modified() {
+                    Ok(modified) => plan.push(Operation::CopyFile { from, to, modified, replace: false, link_group: link_group(&md) }),
+                    Err(e) => error!("Error reading modification time, skipping: '{from}'\n    {e}"),
+                }
+            },
+            Err(e) => error!("Error reading metadata, skipping: '{from}'\n    {e}"),
+        }
+    }
+    warn_skipped_mount_points(&skipped_mount_points);
 }
 
 
@@ -139,20 +218,38 @@ fn copy_file_update_time<P: AsRef<Path>, Q: AsRef<Path>>(from_modified: SystemTi
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub(crate) struct Difference {
     pub(crate) p_source: Option<AnnotatedPath>,
-    pub(crate) p_target: Option<AnnotatedPath>
+    pub(crate) p_target: Option<AnnotatedPath>,
+    /// Set if the file at p_source is the file that was at this path in the target (which is then missing in the source).
+    pub(crate) p_moved_from: Option<AnnotatedPath>,
+    /// Set if the file has to be (un)linked in the target to reflect the hard links in the source.
+    pub(crate) hardlink: Option<HardlinkChange>,
+    /// Set if the file cannot be synchronized, because its name equals that of this file in the target (e.g. only differs in case or normalization).
+    pub(crate) collides_with: Option<AnnotatedPath>
 }
 
 impl Difference {
+    pub(crate) fn new(p_source: Option<AnnotatedPath>, p_target: Option<AnnotatedPath>) -> Difference {
+        Difference { p_source, p_target, p_moved_from: None, hardlink: None, collides_with: None }
+    }
+
     pub(crate) fn describe(&self) -> String {
         let file_name = self.file_name();
 
-        if self.p_source.is_some() && self.p_target.is_some() {
+        if let Some(kept) = &self.collides_with {
+            format!("COLLIDES in backup (names only differ in case or normalization): {}[{}] with {}", self.type_marker(), file_name, kept.relative_path())
+        } else if let (Some(moved_from), Some(ps)) = (&self.p_moved_from, &self.p_source) {
+            format!("MOVED in source: {}[{}]: {} \u{2192} {}", self.type_marker(), file_name, moved_from.relative_path(), ps.relative_path())
+        } else if let Some(HardlinkChange::Link { to }) = &self.hardlink {
+            format!("HARDLINKED in source: FILE[{}] \u{2192} {}", file_name, to.relative_path())
+        } else if matches!(self.hardlink, Some(HardlinkChange::Unlink)) {
+            format!("NO LONGER HARDLINKED in source: FILE[{}]", file_name)
+        } else if let (Some(ps), Some(pt)) = (&self.p_source, &self.p_target) {
             //always a file
-            return format!("MODIFIED ({}): {}[{}]", if self.p_source.as_ref().unwrap().modified() > self.p_target.as_ref().unwrap().modified() { "source is newer" } else { "backup is newer" }, if self.is_dir() { "DIR" } else { "FILE" }, file_name)
-        } else if self.p_source.is_some() && self.p_target.is_none() {
-            return format!("NEW in source (or deleted in backup): {}[{}]", if self.is_dir() { "DIR" } else { "FILE" }, file_name);
-        } else if self.p_source.is_none() && self.p_target.is_some() {
-            return format!("DELETED in source (or new in backup): {}[{}]", if self.is_dir() { "DIR" } else { "FILE" }, file_name);
+            format!("MODIFIED ({}): {}[{}]", if ps.modified() > pt.modified() { "source is newer" } else { "backup is newer" }, self.type_marker(), file_name)
+        } else if self.p_source.is_some() {
+            format!("NEW in source (or deleted in backup): {}[{}]", self.type_marker(), file_name)
+        } else if self.p_target.is_some() {
+            format!("DELETED in source (or new in backup): {}[{}]", self.type_marker(), file_name)
         } else {
             panic!("impossible, this is a bug")
         }
@@ -160,13 +257,21 @@ impl Difference {
     pub(crate) fn describe_short(&self) -> String {
         let file_name = self.file_name();
 
-        if self.p_source.is_some() && self.p_target.is_some() {
+        if let Some(kept) = &self.collides_with {
+            format!("COLLISION: {}[\"{}\"] \u{2194} {}", self.type_marker(), file_name, kept.relative_path())
+        } else if let (Some(moved_from), Some(ps)) = (&self.p_moved_from, &self.p_source) {
+            format!("MOVED: {}[\"{}\"]: {} \u{2192} {}", self.type_marker(), file_name, moved_from.relative_path(), ps.relative_path())
+        } else if let Some(HardlinkChange::Link { to }) = &self.hardlink {
+            format!("LINKED: FILE[\"{}\"] \u{2192} {}", file_name, to.relative_path())
+        } else if matches!(self.hardlink, Some(HardlinkChange::Unlink)) {
+            format!("UNLINKED: FILE[\"{}\"]", file_name)
+        } else if let (Some(ps), Some(pt)) = (&self.p_source, &self.p_target) {
             //always a file
-            return format!("MODIFIED ({}): {}[\"{}\"]", if self.p_source.as_ref().unwrap().modified() > self.p_target.as_ref().unwrap().modified() { "source new" } else { "backup new" }, if self.is_dir() { "DIR" } else { "FILE" }, file_name)
-        } else if self.p_source.is_some() && self.p_target.is_none() {
-            return format!("NEW: {}[\"{}\"]", if self.is_dir() { "DIR" } else { "FILE" }, file_name);
-        } else if self.p_source.is_none() && self.p_target.is_some() {
-            return format!("DELETED: {}[\"{}\"]", if self.is_dir() { "DIR" } else { "FILE" }, file_name);
+            format!("MODIFIED ({}): {}[\"{}\"]", if ps.modified() > pt.modified() { "source new" } else { "backup new" }, self.type_marker(), file_name)
+        } else if self.p_source.is_some() {
+            format!("NEW: {}[\"{}\"]", self.type_marker(), file_name)
+        } else if self.p_target.is_some() {
+            format!("DELETED: {}[\"{}\"]", self.type_marker(), file_name)
         } else {
             panic!("impossible, this is a bug")
         }
@@ -207,6 +312,13 @@ impl Difference {
             panic!("both are none, never happens, bug")
         }
     }
+    pub(crate) fn type_marker(&self) -> &'static str {
+        match (&self.p_source, &self.p_target) {
+            (Some(ps), _) => ps.type_marker(),
+            (None, Some(pt)) => pt.type_marker(),
+            (None, None) => panic!("both are none, never happens, bug"),
+        }
+    }
     pub(crate) fn file_name(&self) -> &str {
         if self.p_source.is_some() /*&& self.p_target.is_some()*/ {
             //filename of both always the same
@@ -239,7 +351,13 @@ impl Difference {
 pub(crate) struct AnnotatedPath {
     pub(crate) path: String,
     name: String,
-    modified: Option<SystemTime>
+    /// The name as it is matched against the other directory, see NameMatching.
+    key: String,
+    modified: Option<SystemTime>,
+    len: u64,
+    root_len: usize,
+    link_group: Option<LinkGroup>,
+    special: Option<SpecialKind>
 }
 impl AnnotatedPath {
     pub fn is_dir(&self) -> bool {
@@ -248,18 +366,50 @@ impl AnnotatedPath {
     pub fn modified(&self) -> SystemTime {
         return self.modified.expect("cannot query modified for directories for reasons of fs independence")
     }
+    /// Size in bytes, 0 for directories.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    /// Set for FIFOs, sockets and device nodes, which are otherwise treated like files.
+    pub fn special(&self) -> Option<SpecialKind> {
+        self.special
+    }
+    pub fn type_marker(&self) -> &'static str {
+        match self.special {
+            Some(kind) => kind.marker(),
+            None if self.is_dir() => "DIR",
+            None => "FILE",
+        }
+    }
+    /// (device, inode) of files that have more than one hard link.
+    pub fn link_group(&self) -> Option<LinkGroup> {
+        self.link_group
+    }
+    /// Path relative to the source or target directory it was found in.
+    pub fn relative_path(&self) -> &str {
+        self.path[self.root_len..].trim_start_matches('/')
+    }
+    /// The same entry under another name in the same directory.
+    fn renamed(&self, name: &str) -> AnnotatedPath {
+        let parent = Path::new(&self.path).parent().and_then(|p| p.to_str()).unwrap_or("");
+        AnnotatedPath { path: format!("{parent}/{name}"), name: name.to_string(), ..self.clone() }
+    }
+    /// The same entry with the same name in another directory.
+    fn moved_to(&self, dir: &str) -> AnnotatedPath {
+        AnnotatedPath { path: format!("{dir}/{}", self.name), ..self.clone() }
+    }
 }
 
 impl Eq for AnnotatedPath {}
 impl Hash for AnnotatedPath {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        return self.name.hash(state);
+        self.key.hash(state);
     }
 }
 
 impl PartialEq<Self> for AnnotatedPath {
     fn eq(&self, other: &Self) -> bool {
-        return self.name == other.name
+        self.key == other.key
     }
 }
 impl PartialOrd<Self> for AnnotatedPath {
@@ -280,7 +430,7 @@ impl Ord for AnnotatedPath {
 ///               that target directory does not contain any files that don't exist in source,\n    \
 ///               but are newer than the last common modification date (assumed time of last synchronization).
 /// Returns list of files that are assumed newer in target directory ("problems").
-pub(crate) fn verify_source_fully_newer_than_target(differences: &Vec<Difference>) -> HashMap<Difference, String> {
+pub(crate) fn verify_source_fully_newer_than_target(differences: &Vec<Difference>, options: &SyncOptions) -> HashMap<Difference, String> {
     let mut problems = HashMap::new();
 
     if differences.is_empty() {
@@ -289,7 +439,7 @@ pub(crate) fn verify_source_fully_newer_than_target(differences: &Vec<Difference
 
     let mut assumed_time_of_divergence = SystemTime::UNIX_EPOCH;
     for d in differences {
-        if d.is_dir() {continue}
+        if d.is_dir() || d.hardlink.is_some() || d.collides_with.is_some() {continue}
         if d.p_source.is_some() && d.p_target.is_some() {
             assumed_time_of_divergence = assumed_time_of_divergence.max(d.ps_modified());
         } else if d.p_source.is_none() && d.p_target.is_some() {
@@ -299,83 +449,510 @@ pub(crate) fn verify_source_fully_newer_than_target(differences: &Vec<Difference
     }
 
     for d in differences {
-        if d.p_source.is_some() && d.p_target.is_some() {
-            if !d.is_dir() && d.pt_modified() > d.ps_modified() {
+        if let Some(kept) = &d.collides_with {
+            problems.insert(d.clone(), format!("Name collides with '{}' in the backup directory (differs only in case or Unicode normalization), only one of them can be synchronized.", kept.relative_path()));
+        } else if options.special_files == SpecialFilePolicy::Error && d.p_source.as_ref().is_some_and(|ps| ps.special().is_some()) {
+            problems.insert(d.clone(), format!("Special file ({}) in source directory, which cannot be synchronized (policy: error).", d.type_marker()));
+        } else if d.p_source.is_some() && d.p_target.is_some() {
+            if !d.is_dir() && is_newer(d.pt_modified(), d.ps_modified(), options.mtime_tolerance.duration()) {
                 problems.insert(d.clone(), "NEWER in backup directory".to_string());
             }
         } else if d.p_source.is_none() && d.p_target.is_some() {
             if d.is_dir() {
                 problems.insert(d.clone(), "Directory exists in backup directory, but NOT in source directory.".to_string());
-            } else if d.pt_modified() >= assumed_time_of_divergence {
+            } else if !is_newer(assumed_time_of_divergence, d.pt_modified(), options.mtime_tolerance.duration()) {
                 problems.insert(d.clone(), "File exists in backup directory, but NOT in source directory and cannot be verified to be old.".to_string());
             }
         }
     }
 
-    return problems
+    problems
 }
 
 
 
-pub(crate) fn find_differences(source_dir: &str, target_dir: &str) -> Vec<Difference> {
+pub(crate) fn find_differences(source_dir: &str, target_dir: &str, options: &SyncOptions) -> Vec<Difference> {
+    find_differences_with(&SourceListing::default(), source_dir, target_dir, options)
+}
+
+/// Finds the differences, taking the entries of the source from the listing, so that it is only listed once for several targets.
+pub(crate) fn find_differences_with(source_listing: &SourceListing, source_dir: &str, target_dir: &str, options: &SyncOptions) -> Vec<Difference> {
     let mut collector = Vec::with_capacity(64);
 
-    find_differences_rec(
+    let mut scanner = Scanner::new(source_dir, target_dir, options, source_listing);
+    scanner.find_differences_rec(
         source_dir, target_dir,
-         &mut |s, t| collector.push(Difference { p_source: s.cloned(), p_target: t.cloned() })
+         &mut |d| collector.push(d)
     );
+    scanner.finish(&mut collector);
+
+    if options.move_detection != MoveDetection::Off {
+        detect_moves(&mut collector, options.move_detection);
+    }
+
+    collector
+}
+
+/// Finds the differences of a single path relative to the roots (and below it, if it is a directory on both sides),
+/// without looking at the rest of the trees. Used to apply changes reported by the watch mode.
+/// Moves and hard links are not detected, as their other ends may lie outside of the path.
+pub(crate) fn find_differences_at(source_dir: &str, target_dir: &str, relative_path: &str, options: &SyncOptions) -> Vec<Difference> {
+    let mut collector = Vec::new();
+    let relative = Path::new(relative_path);
+    let (Some(name), Some(parent)) = (relative.file_name().and_then(|n| n.to_str()), relative.parent()) else { return collector };
+    let is_state_dir = relative.components().next().is_some_and(|c| c.as_os_str() == STATE_DIR_NAME);
+    if is_state_dir || options.excludes.is_excluded(relative_path, fs::metadata(Path::new(source_dir).join(relative)).is_ok_and(|m| m.is_dir())) {
+        return collector;
+    }
+
+    let source_listing = SourceListing::default();
+    let mut scanner = Scanner::new(source_dir, target_dir, options, &source_listing);
+    let source_entry = fs::symlink_metadata(Path::new(source_dir).join(relative)).ok().map(|meta| {
+        let path = Path::new(source_dir).join(relative).to_str().unwrap().to_string();
+        annotated_path(path, name.to_string(), &meta, source_dir.len())
+    });
+    //looked up by key, so that names differing only in case are found in case-insensitive targets
+    let target_parent = Path::new(target_dir).join(parent).to_str().unwrap().to_string();
+    let mut collisions = Vec::new();
+    let target_entries = keyed_paths(read_entries(&target_parent, target_dir.len()), &scanner.name_matching, &mut collisions);
+    let key = scanner.name_matching.key(name);
+    let target_entry = target_entries.into_iter().find(|p| p.key == key);
+
+    match (source_entry, target_entry) {
+        (Some(mut ps), pt) => {
+            ps.key = key;
+            scanner.compare(&ps, pt.as_ref(), &mut |d| collector.push(d));
+        }
+        (None, Some(pt)) => collector.push(Difference::new(None, Some(pt))),
+        (None, None) => {}
+    }
+    collector
+}
+
+/// Pairs files and directories deleted in the source with new ones of the same content and replaces each pair with a single moved difference.
+/// Files are paired if they have the same size and modification time, directories if they contain the same entries
+/// with the same sizes and modification times. With MoveDetection::Hash, the contents are additionally compared.
+/// Entries that cannot be paired unambiguously are left alone.
+fn detect_moves(differences: &mut Vec<Difference>, move_detection: MoveDetection) {
+    let mut deleted_by_key: HashMap<MoveKey, Vec<usize>> = HashMap::new();
+    for (i, d) in differences.iter().enumerate() {
+        if let (None, Some(pt)) = (&d.p_source, &d.p_target) {
+            if pt.special().is_none() && d.collides_with.is_none() {
+                if let Some(key) = MoveKey::of(pt) {
+                    deleted_by_key.entry(key).or_default().push(i);
+                }
+            }
+        }
+    }
+    if deleted_by_key.is_empty() {
+        return;
+    }
+    let directories_deleted = deleted_by_key.keys().any(|key| matches!(key, MoveKey::Dir(_)));
+
+    let mut fingerprints = Fingerprints::default();
+    let mut paired_deletions = HashSet::new();
+    for i in 0..differences.len() {
+        let ps = match (&differences[i].p_source, &differences[i].p_target) {
+            (Some(ps), None) if ps.special().is_none() && differences[i].p_moved_from.is_none() && differences[i].collides_with.is_none() => ps,
+            _ => continue,
+        };
+        if ps.is_dir() && !directories_deleted {
+            continue;
+        }
+        let Some(candidates) = MoveKey::of(ps).and_then(|key| deleted_by_key.get_mut(&key)) else { continue };
+        let matching: Vec<usize> = match move_detection {
+            MoveDetection::Hash => {
+                let Some(fingerprint) = fingerprints.of(ps) else { continue };
+                (0..candidates.len())
+                    .filter(|&c| {
+                        let pt = differences[candidates[c]].p_target.as_ref().unwrap();
+                        fingerprints.of(pt) == Some(fingerprint) && same_content(&ps.path, &pt.path, ps.is_dir()).unwrap_or(false)
+                    })
+                    .collect()
+            }
+            _ => (0..candidates.len()).collect(),
+        };
+        if matching.is_empty() { continue }
+        let same_name: Vec<usize> = matching.iter().copied().filter(|&c| differences[candidates[c]].file_name() == ps.name).collect();
+        let chosen = if matching.len() == 1 || (move_detection == MoveDetection::Hash && same_name.is_empty()) {
+            //with equal contents, any of the candidates will do
+            matching[0]
+        } else if same_name.len() == 1 {
+            same_name[0]
+        } else {
+            continue
+        };
+        let deletion = candidates.remove(chosen);
+        differences[i].p_moved_from = differences[deletion].p_target.clone();
+        paired_deletions.insert(deletion);
+    }
 
-    return collector
+    let mut i = 0;
+    differences.retain(|_| { i += 1; !paired_deletions.contains(&(i - 1)) });
 }
 
-fn find_differences_rec(dir1: &str, dir2: &str, found_difference_callback: &mut dyn FnMut(Option<&AnnotatedPath>, Option<&AnnotatedPath>)) {
-    let dir1_set = list_paths(dir1);
-    let dir2_set = list_paths(dir2);
+/// What a deleted and a new entry have to share to be paired as a move.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum MoveKey {
+    File { len: u64, modified: SystemTime },
+    /// The relative path, whether it is a directory, the size and the modification time of every entry below the directory
+    /// (sorted, the sizes and times of directories are left out).
+    Dir(Vec<(String, bool, u64, Option<SystemTime>)>),
+}
 
-    for f2 in &dir2_set {
-        let f1o = dir1_set.get(f2);
-        if f1o.is_none() {
-            found_difference_callback(None, Some(f2));
+impl MoveKey {
+    /// None for empty directories, which are as cheap to create as to move, and for directories that cannot be fully read.
+    fn of(path: &AnnotatedPath) -> Option<MoveKey> {
+        if !path.is_dir() {
+            return Some(MoveKey::File { len: path.len(), modified: path.modified() });
+        }
+        let mut entries = Vec::new();
+        for entry in walkdir::WalkDir::new(&path.path).min_depth(1).sort_by_file_name() {
+            let entry = entry.ok()?;
+            let relative = entry.path().strip_prefix(&path.path).ok()?.to_str()?.to_string();
+            let metadata = entry.metadata().ok()?;
+            entries.push(match metadata.is_dir() {
+                true => (relative, true, 0, None),
+                false => (relative, false, metadata.len(), Some(metadata.modified().ok()?)),
+            });
         }
+        (!entries.is_empty()).then_some(MoveKey::Dir(entries))
     }
+}
+
+/// Content fingerprints of the move candidates, each computed once.
+/// Equal fingerprints only preselect candidates, their contents are compared before they are paired.
+#[derive(Default)]
+struct Fingerprints {
+    by_path: HashMap<String, Option<u64>>,
+}
 
-    for f1 in &dir1_set {
-        let f2o = dir2_set.get(f1);
-        if f2o.is_some() {
-            let f2 = f2o.unwrap();
+impl Fingerprints {
+    fn of(&mut self, path: &AnnotatedPath) -> Option<u64> {
+        *self.by_path.entry(path.path.clone()).or_insert_with(|| fingerprint(&path.path, path.is_dir()).ok())
+    }
+}
+
+/// Hash of the contents of a file, or of the relative paths and file contents below a directory.
+fn fingerprint(path: &str, is_dir: bool) -> io::Result<u64> {
+    let mut hasher = XxHash64::default();
+    if !is_dir {
+        hash_content(path, &mut hasher)?;
+        return Ok(hasher.finish());
+    }
+    for entry in walkdir::WalkDir::new(path).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        entry.path().strip_prefix(path).map_err(io::Error::other)?.hash(&mut hasher);
+        if !entry.file_type().is_dir() {
+            hash_content(&entry.path().to_string_lossy(), &mut hasher)?;
+        }
+    }
+    Ok(hasher.finish())
+}
+
+fn hash_content(path: &str, hasher: &mut impl Hasher) -> io::Result<()> {
+    let mut reader = fs::File::open(path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => hasher.write(&buf[..len]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether two files, or the files below two directories with the same entries, have the same contents, compared byte for byte.
+fn same_content(path1: &str, path2: &str, is_dir: bool) -> io::Result<bool> {
+    if !is_dir {
+        return files_equal(path1, path2);
+    }
+    for entry in walkdir::WalkDir::new(path1).min_depth(1) {
+        let entry = entry?;
+        if !entry.file_type().is_dir() {
+            let relative = entry.path().strip_prefix(path1).map_err(io::Error::other)?;
+            if !files_equal(&entry.path().to_string_lossy(), &Path::new(path2).join(relative).to_string_lossy())? {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Compares the contents of two files byte for byte, reading both in chunks.
+pub(crate) fn files_equal(path1: &str, path2: &str) -> io::Result<bool> {
+    let (mut file1, mut file2) = (fs::File::open(path1)?, fs::File::open(path2)?);
+    if file1.metadata()?.len() != file2.metadata()?.len() {
+        return Ok(false);
+    }
+    let (mut buf1, mut buf2) = (vec![0u8; 64 * 1024], vec![0u8; 64 * 1024]);
+    loop {
+        let len = read_full(&mut file1, &mut buf1)?;
+        if len != read_full(&mut file2, &mut buf2)? || buf1[..len] != buf2[..len] {
+            return Ok(false);
+        }
+        if len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fills the buffer as far as the file allows, so that chunks of both files can be compared.
+fn read_full(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Entries of the directories of a source, each directory is read when it is first needed.
+/// Shared by the scans of one source against several targets, which then see the source as it was when first listed.
+#[derive(Debug, Default)]
+pub(crate) struct SourceListing {
+    directories: RefCell<HashMap<String, Rc<Vec<AnnotatedPath>>>>,
+}
+
+impl SourceListing {
+    fn entries(&self, dir: &str, root_len: usize) -> Rc<Vec<AnnotatedPath>> {
+        if let Some(entries) = self.directories.borrow().get(dir) {
+            return entries.clone();
+        }
+        let entries = Rc::new(read_entries(dir, root_len));
+        self.directories.borrow_mut().insert(dir.to_string(), entries.clone());
+        entries
+    }
+}
+
+/// Walks source and target directory side by side, reporting differences as they are found.
+/// Collects what can only be decided once both trees are fully known.
+struct Scanner<'a> {
+    root_lens: (usize, usize),
+    links: LinkTracker,
+    /// Devices of the source and target root, if the scan has to stay on them (--one-file-system).
+    root_devices: Option<(u64, u64)>,
+    skipped_mount_points: Vec<String>,
+    /// Target directories renamed to the name in the source (new path, path on disk until the rename is applied).
+    renamed_dirs: Vec<(String, String)>,
+    mtime_tolerance: Duration,
+    name_matching: NameMatching,
+    excludes: Excludes,
+    comparison: Comparison,
+    source_listing: &'a SourceListing,
+    /// Once cancelled, no further directories are compared.
+    progress: Option<Arc<Progress>>,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(source_dir: &str, target_dir: &str, options: &SyncOptions, source_listing: &'a SourceListing) -> Scanner<'a> {
+        let root_devices = if options.one_file_system {
+            device_id(source_dir).zip(device_id(target_dir))
+        } else {
+            None
+        };
+        Scanner { root_lens: (source_dir.len(), target_dir.len()), links: LinkTracker::default(), root_devices, skipped_mount_points: Vec::new(), renamed_dirs: Vec::new(), mtime_tolerance: options.mtime_tolerance.duration(), name_matching: options.name_matching(), excludes: options.excludes.clone(), comparison: options.comparison, source_listing, progress: options.progress.clone() }
+    }
+
+    fn find_differences_rec(&mut self, dir1: &str, dir2: &str, found_difference_callback: &mut dyn FnMut(Difference)) {
+        if let Some(progress) = &self.progress {
+            if progress.is_cancelled() {
+                return;
+            }
+            progress.directory_scanned();
+        }
+        let mut collisions = Vec::new();
+        let source_entries = self.source_listing.entries(dir1, self.root_lens.0);
+        let mut dir1_set = keyed_paths(source_entries.iter().cloned(), &self.name_matching, &mut collisions);
+        let source_collisions = collisions.len();
+        let mut dir2_set = keyed_paths(self.target_entries(dir2), &self.name_matching, &mut collisions);
+        //the synchronizer's own files are neither synchronized nor deleted
+        dir1_set.retain(|p| p.relative_path() != STATE_DIR_NAME && !self.excludes.is_excluded(p.relative_path(), p.is_dir()));
+        dir2_set.retain(|p| p.relative_path() != STATE_DIR_NAME && !self.excludes.is_excluded(p.relative_path(), p.is_dir()));
+        for (i, (kept, dropped)) in collisions.into_iter().enumerate() {
+            let mut collision = if i < source_collisions { Difference::new(Some(dropped), None) } else { Difference::new(None, Some(dropped)) };
+            collision.collides_with = Some(kept);
+            found_difference_callback(collision);
+        }
+        if let Some((source_device, target_device)) = self.root_devices {
+            self.skip_mount_points(&mut dir1_set, source_device);
+            self.skip_mount_points(&mut dir2_set, target_device);
+        }
+
+        for f2 in &dir2_set {
+            let f1o = dir1_set.get(f2);
+            if f1o.is_none() {
+                found_difference_callback(Difference::new(None, Some(f2.clone())));
+            }
+        }
+
+        for f1 in &dir1_set {
+            self.compare(f1, dir2_set.get(f1), found_difference_callback);
+        }
+    }
+
+    /// Compares an entry of the source with the entry of the same key in the target (if any), descending into directories.
+    fn compare(&mut self, f1: &AnnotatedPath, f2o: Option<&AnnotatedPath>, found_difference_callback: &mut dyn FnMut(Difference)) {
+        if let Some(f2) = f2o {
+            let renamed_f2;
+            let f2 = if f1.name != f2.name && f1.is_dir() == f2.is_dir() {
+                //names only differ in case or normalization, the target is renamed before anything else happens to it,
+                //so the differences below refer to it by its new name
+                let mut renamed = Difference::new(Some(f1.clone()), None);
+                renamed.p_moved_from = Some(f2.clone());
+                found_difference_callback(renamed);
+                renamed_f2 = f2.renamed(&f1.name);
+                if f2.is_dir() {
+                    self.renamed_dirs.push((renamed_f2.path.clone(), f2.path.clone()));
+                }
+                &renamed_f2
+            } else {
+                f2
+            };
+            let f2o = Some(f2);
             if f1.is_dir() && f2.is_dir() {
-                find_differences_rec(&f1.path, &f2.path, found_difference_callback);
+                self.find_differences_rec(&f1.path, &f2.path, found_difference_callback);
             } else {
-                if f1.is_dir() != f2.is_dir() || f1.modified != f2.modified {
-                    found_difference_callback(Some(f1), f2o);
+                let changed = f1.is_dir() != f2.is_dir() || self.contents_differ(f1, f2);
+                if changed {
+                    found_difference_callback(Difference::new(Some(f1.clone()), f2o.cloned()));
+                }
+                if !f1.is_dir() && !f2.is_dir() {
+                    self.links.record(f1, f2o, !changed);
                 }
             }
         } else {
-            found_difference_callback(Some(f1), None);
+            found_difference_callback(Difference::new(Some(f1.clone()), None));
+            if !f1.is_dir() {
+                self.links.record(f1, None, false);
+            }
+        }
+    }
+
+    /// Entries of a target directory, which are still read from the old path if the directory (or one above it) is not renamed yet.
+    fn target_entries(&self, dir: &str) -> Vec<AnnotatedPath> {
+        if !fs::metadata(dir).is_ok_and(|m| m.is_dir()) {
+            let on_disk = self.renamed_dirs.iter().rev()
+                .find_map(|(new, old)| Path::new(dir).strip_prefix(new).ok().map(|rest| Path::new(old).join(rest)));
+            if let Some(on_disk) = on_disk.as_ref().and_then(|p| p.to_str()) {
+                return read_entries(on_disk, self.root_lens.1).into_iter().map(|p| p.moved_to(dir)).collect();
+            }
         }
+        read_entries(dir, self.root_lens.1)
     }
+
+    /// Whether two files (not directories) differ, as decided by the comparison method.
+    fn contents_differ(&self, f1: &AnnotatedPath, f2: &AnnotatedPath) -> bool {
+        if self.comparison == Comparison::Checksum && f1.special().is_none() && f2.special().is_none() {
+            //files that cannot be read are assumed to differ
+            return f1.len() != f2.len() || !files_equal(&f1.path, &f2.path).unwrap_or(false);
+        }
+        match (f1.modified, f2.modified) {
+            (Some(m1), Some(m2)) => !mtimes_equal(m1, m2, self.mtime_tolerance),
+            (m1, m2) => m1 != m2,
+        }
+    }
+
+    /// Removes directories on another device than the root, they are neither compared nor descended into.
+    fn skip_mount_points(&mut self, paths: &mut HashSet<AnnotatedPath>, root_device: u64) {
+        paths.retain(|p| {
+            let is_mount_point = p.is_dir() && device_id(&p.path).is_some_and(|device| device != root_device);
+            if is_mount_point {
+                self.skipped_mount_points.push(p.path.clone());
+            }
+            !is_mount_point
+        });
+    }
+
+    /// Adds the differences that could only be determined after the scan.
+    fn finish(self, differences: &mut Vec<Difference>) {
+        warn_skipped_mount_points(&self.skipped_mount_points);
+        self.links.link_differences(differences);
+    }
+}
+
+fn warn_skipped_mount_points(paths: &[String]) {
+    if !paths.is_empty() {
+        let paths: Vec<String> = paths.iter().map(|path| format!("    '{path}'")).collect();
+        warn!("Skipped mount points (--one-file-system):\n{}", paths.join("\n"));
+    }
+}
+
+/// Device of a directory that is walked to copy or remove it, if the walk has to stay on it (--one-file-system).
+fn walk_root_device(dir: &str, options: &SyncOptions) -> Option<u64> {
+    options.one_file_system.then(|| device_id(dir)).flatten()
 }
 
+/// Whether the entry is a directory on another device than the root of the walk.
+/// walkdir does not descend into it with `same_file_system`, but still yields it.
+fn is_mount_point(entry: &walkdir::DirEntry, root_device: Option<u64>) -> bool {
+    root_device.is_some_and(|root_device| entry.file_type().is_dir()
+        && device_id(&entry.path().to_string_lossy()).is_some_and(|device| device != root_device))
+}
+
+#[cfg(unix)]
+fn device_id(path: &str) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_path: &str) -> Option<u64> {
+    None
+}
 
-fn list_paths(dir: &str) -> HashSet<AnnotatedPath> {
-    return match fs::read_dir(dir) {
+
+/// Lists the entries of the directory, keyed by their name.
+fn read_entries(dir: &str, root_len: usize) -> Vec<AnnotatedPath> {
+    match fs::read_dir(dir) {
         Ok(reader) => {
-            let mut result = HashSet::new();
+            let mut result = Vec::new();
             for r in reader {
                 let e = r.unwrap();
                 let path = e.path().to_str().unwrap().to_string();
                 let name = e.file_name().to_str().unwrap().to_string();
                 let meta = e.metadata().unwrap();
-                let is_dir = meta.is_dir();
-                let modified = if is_dir {
-                    None
-                } else {
-                    Some(meta.modified().unwrap())
-                };
-                result.insert(AnnotatedPath { path, name, modified });
+                result.push(annotated_path(path, name, &meta, root_len));
             }
             result
         }
-        Err(_) => { HashSet::with_capacity(0) }
+        Err(_) => { Vec::with_capacity(0) }
+    }
+}
+
+/// The entry at the path, keyed by its name.
+fn annotated_path(path: String, name: String, meta: &fs::Metadata, root_len: usize) -> AnnotatedPath {
+    let is_dir = meta.is_dir();
+    let modified = if is_dir {
+        None
+    } else {
+        Some(meta.modified().unwrap())
+    };
+    let len = if is_dir { 0 } else { meta.len() };
+    let link_group = link_group(meta);
+    let special = special_kind(meta);
+    let key = name.clone();
+    AnnotatedPath { path, name, key, modified, len, root_len, link_group, special }
+}
+
+/// Keys the entries as given by the name matching.
+/// Of entries with equal keys only the first by name is kept, the others are added to the collisions as (kept, dropped).
+fn keyed_paths(entries: impl IntoIterator<Item=AnnotatedPath>, matching: &NameMatching, collisions: &mut Vec<(AnnotatedPath, AnnotatedPath)>) -> HashSet<AnnotatedPath> {
+    let mut result: HashSet<AnnotatedPath> = HashSet::new();
+    for mut annotated in entries {
+        annotated.key = matching.key(&annotated.name);
+        if let Some(existing) = result.take(&annotated) {
+            let (kept, dropped) = if existing.name <= annotated.name { (existing, annotated) } else { (annotated, existing) };
+            collisions.push((kept.clone(), dropped));
+            result.insert(kept);
+        } else {
+            result.insert(annotated);
+        }
     }
+    result
 }
@@ -0,0 +1,89 @@
+/// Paths that are neither compared nor synchronized, in source and target.
+///
+/// Patterns are matched like in .gitignore:
+/// a pattern without "/" matches the name of an entry at any depth ("*.tmp", "node_modules"),
+/// a pattern with "/" matches the path relative to the root ("build/cache", "docs/**/*.pdf"),
+/// a trailing "/" only matches directories ("cache/").
+/// "*" matches any part of a name, "**" any number of directories and "?" a single character.
+/// Excluded directories are skipped entirely.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Excludes {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    /// Components of the pattern, matched against the components of the relative path.
+    components: Vec<String>,
+    anchored: bool,
+    directories_only: bool,
+}
+
+impl Excludes {
+    pub(crate) fn add(&mut self, pattern: &str) {
+        let directories_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        if pattern.is_empty() {
+            return;
+        }
+        self.patterns.push(Pattern {
+            components: pattern.trim_start_matches('/').split('/').map(str::to_string).collect(),
+            anchored: pattern.contains('/'),
+            directories_only,
+        });
+    }
+
+    pub(crate) fn add_all<S: AsRef<str>>(&mut self, patterns: &[S]) {
+        for pattern in patterns {
+            self.add(pattern.as_ref());
+        }
+    }
+
+    /// Whether the entry at the path relative to the source or target root is excluded.
+    pub(crate) fn is_excluded(&self, relative_path: &str, is_dir: bool) -> bool {
+        let components: Vec<&str> = relative_path.split('/').filter(|c| !c.is_empty()).collect();
+        self.patterns.iter().any(|pattern| {
+            if pattern.directories_only && !is_dir {
+                return false;
+            }
+            if pattern.anchored {
+                matches_components(&pattern.components, &components)
+            } else {
+                components.last().is_some_and(|name| matches_name(&pattern.components[0], name))
+            }
+        })
+    }
+}
+
+fn matches_components(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => (0..=path.len()).any(|skipped| matches_components(rest, &path[skipped..])),
+        Some((first, rest)) => path.split_first().is_some_and(|(name, path)| matches_name(first, name) && matches_components(rest, path)),
+    }
+}
+
+/// Matches a single name against a pattern with "*" and "?".
+fn matches_name(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    //position after the last "*" in the pattern and the name position it currently matches up to
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
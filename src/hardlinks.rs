@@ -0,0 +1,126 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::Metadata;
+use crate::differences::{AnnotatedPath, Difference};
+
+/// (device, inode) of a file that has more than one hard link.
+pub(crate) type LinkGroup = (u64, u64);
+
+/// Returns the (device, inode) pair identifying the file, if there are other hard links to it.
+#[cfg(unix)]
+pub(crate) fn link_group(meta: &Metadata) -> Option<LinkGroup> {
+    use std::os::unix::fs::MetadataExt;
+    if meta.is_file() && meta.nlink() > 1 {
+        Some((meta.dev(), meta.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn link_group(_meta: &Metadata) -> Option<LinkGroup> {
+    None
+}
+
+/// How the hard link structure of a file has to change in the target.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub(crate) enum HardlinkChange {
+    /// The file is a hard link to the given source file, so it has to be linked to the counterpart of that file in the target.
+    Link { to: AnnotatedPath },
+    /// The file is linked to other files in the target, but not in the source.
+    Unlink,
+}
+
+struct Member {
+    source: AnnotatedPath,
+    target: Option<AnnotatedPath>,
+    unchanged: bool,
+}
+
+/// Collects files with multiple hard links during scanning,
+/// so that differences in link structure can be determined once the whole tree is known.
+#[derive(Default)]
+pub(crate) struct LinkTracker {
+    by_source_group: HashMap<LinkGroup, Vec<Member>>,
+    by_target_group: HashMap<LinkGroup, Vec<Member>>,
+}
+
+impl LinkTracker {
+    /// Records a source file and its target counterpart (None if it is new), if either of them is hard linked.
+    pub(crate) fn record(&mut self, source: &AnnotatedPath, target: Option<&AnnotatedPath>, unchanged: bool) {
+        if let Some(group) = source.link_group() {
+            self.by_source_group.entry(group).or_default().push(Member { source: source.clone(), target: target.cloned(), unchanged });
+        }
+        if let Some(group) = target.filter(|_| unchanged).and_then(|t| t.link_group()) {
+            self.by_target_group.entry(group).or_default().push(Member { source: source.clone(), target: target.cloned(), unchanged });
+        }
+    }
+
+    /// Marks new and modified differences that have to be hard linked instead of copied
+    /// and adds differences for unchanged files that are linked differently in source and target.
+    pub(crate) fn link_differences(self, differences: &mut Vec<Difference>) {
+        let mut index_by_source_path: HashMap<String, usize> = HashMap::new();
+        for (i, d) in differences.iter().enumerate() {
+            if let Some(ps) = &d.p_source {
+                index_by_source_path.insert(ps.path.clone(), i);
+            }
+        }
+
+        let mut linked_source_paths = HashSet::new();
+        for mut members in self.by_source_group.into_values().filter(|m| m.len() > 1) {
+            members.sort_by(|a, b| a.source.cmp(&b.source));
+            //the others are linked to the file that is already linked to the most others in the target
+            let mut target_group_sizes: HashMap<LinkGroup, usize> = HashMap::new();
+            for m in members.iter().filter(|m| m.unchanged) {
+                if let Some(group) = m.target.as_ref().unwrap().link_group() {
+                    *target_group_sizes.entry(group).or_default() += 1;
+                }
+            }
+            let Some((anchor, _)) = members.iter().enumerate()
+                .filter(|(_, m)| m.unchanged)
+                .min_by_key(|(i, m)| (std::cmp::Reverse(m.target.as_ref().unwrap().link_group().map_or(1, |g| target_group_sizes[&g])), *i))
+                else { continue };
+            let anchor_target_group = members[anchor].target.as_ref().unwrap().link_group();
+            for (i, m) in members.iter().enumerate() {
+                if i == anchor {
+                    continue;
+                }
+                let link = HardlinkChange::Link { to: members[anchor].source.clone() };
+                if !m.unchanged {
+                    if let Some(&d) = index_by_source_path.get(&m.source.path) {
+                        differences[d].hardlink = Some(link);
+                    }
+                } else if anchor_target_group.is_none() || m.target.as_ref().unwrap().link_group() != anchor_target_group {
+                    let mut d = Difference::new(Some(m.source.clone()), m.target.clone());
+                    d.hardlink = Some(link);
+                    differences.push(d);
+                    linked_source_paths.insert(m.source.path.clone());
+                }
+            }
+        }
+
+        for mut members in self.by_target_group.into_values().filter(|m| m.len() > 1) {
+            members.sort_by(|a, b| a.source.cmp(&b.source));
+            //the largest set of members that is also linked in the source stays linked, all others are unlinked
+            let mut source_group_sizes: HashMap<LinkGroup, usize> = HashMap::new();
+            for m in &members {
+                if let Some(group) = m.source.link_group() {
+                    *source_group_sizes.entry(group).or_default() += 1;
+                }
+            }
+            let kept_group = members.iter()
+                .filter_map(|m| m.source.link_group())
+                .max_by_key(|group| source_group_sizes[group]);
+            for (i, m) in members.iter().enumerate() {
+                let kept = match kept_group {
+                    Some(group) => m.source.link_group() == Some(group),
+                    None => i == 0,
+                };
+                if !kept && !linked_source_paths.contains(&m.source.path) {
+                    let mut d = Difference::new(Some(m.source.clone()), m.target.clone());
+                    d.hardlink = Some(HardlinkChange::Unlink);
+                    differences.push(d);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::process::{Command, Stdio};
+use log::{info, warn};
+use crate::apply::ApplyReport;
+use crate::cli::Mode;
+use crate::differences::{apply_after_analysis_with_prints, apply_during_analysis_with_prints, SourceListing};
+use crate::options::SyncOptions;
+use crate::outcome::Outcome;
+
+/// Prefix of the environment variables that pass the context of a run to its hooks.
+const ENV_PREFIX: &str = "DIRSYNC_";
+
+/// Shell commands run around the analysis and the application of the differences to a target,
+/// e.g. to snapshot a database or stop a service before the backup and to restart it afterwards.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Hooks {
+    /// Before the target is analyzed, a failure aborts the run of the target.
+    pub(crate) pre_scan: Option<String>,
+    /// Once all differences are known.
+    pub(crate) post_scan: Option<String>,
+    /// Before differences are applied (only if there are any), a failure aborts the run of the target.
+    pub(crate) pre_apply: Option<String>,
+    /// At the end of every run that syncs, also if nothing was applied or the run failed, so that e.g. a stopped service is restarted.
+    pub(crate) post_apply: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Hook {
+    PreScan,
+    PostScan,
+    PreApply,
+    PostApply,
+}
+
+impl Hook {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Hook::PreScan => "pre-scan",
+            Hook::PostScan => "post-scan",
+            Hook::PreApply => "pre-apply",
+            Hook::PostApply => "post-apply",
+        }
+    }
+}
+
+impl Hooks {
+    fn command(&self, hook: Hook) -> Option<&str> {
+        match hook {
+            Hook::PreScan => &self.pre_scan,
+            Hook::PostScan => &self.post_scan,
+            Hook::PreApply => &self.pre_apply,
+            Hook::PostApply => &self.post_apply,
+        }.as_deref()
+    }
+
+    /// Sets the command of the hook, an empty command removes it (e.g. to disable a global hook in a profile).
+    pub(crate) fn set(&mut self, hook: Hook, command: &str) {
+        let command = Some(command.to_string()).filter(|c| !c.trim().is_empty());
+        match hook {
+            Hook::PreScan => self.pre_scan = command,
+            Hook::PostScan => self.post_scan = command,
+            Hook::PreApply => self.pre_apply = command,
+            Hook::PostApply => self.post_apply = command,
+        }
+    }
+
+    /// Whether a hook runs between finding and applying the differences, so that all have to be found first.
+    pub(crate) fn between_scan_and_apply(&self) -> bool {
+        self.post_scan.is_some() || self.pre_apply.is_some()
+    }
+}
+
+/// The hooks of the run of a single target.
+///
+/// Each hook is run with "sh -c" and gets the context in environment variables:
+/// DIRSYNC_HOOK (e.g. pre-scan), DIRSYNC_MODE, DIRSYNC_SOURCE and DIRSYNC_TARGET,
+/// from post-scan on DIRSYNC_DIFFERENCES and DIRSYNC_PROBLEMS,
+/// for post-apply DIRSYNC_OUTCOME, DIRSYNC_EXIT_CODE, DIRSYNC_OPERATIONS, DIRSYNC_FAILED, DIRSYNC_BYTES_WRITTEN and DIRSYNC_SUMMARY.
+/// The output of the hooks is logged.
+pub(crate) struct RunHooks<'a> {
+    hooks: &'a Hooks,
+    mode: Mode,
+    source_path: &'a str,
+    target_path: &'a str,
+}
+
+impl<'a> RunHooks<'a> {
+    pub(crate) fn new(options: &'a SyncOptions, mode: Mode, source_path: &'a str, target_path: &'a str) -> RunHooks<'a> {
+        RunHooks { hooks: &options.hooks, mode, source_path, target_path }
+    }
+
+    pub(crate) fn between_scan_and_apply(&self) -> bool {
+        self.hooks.between_scan_and_apply()
+    }
+
+    pub(crate) fn pre_scan(&self) -> Result<(), String> {
+        self.run(Hook::PreScan, &[]).map_err(|e| format!("Aborting, {e}."))
+    }
+
+    pub(crate) fn post_scan(&self, differences: usize, problems: usize) {
+        self.run_and_warn(Hook::PostScan, &scan_vars(differences, problems));
+    }
+
+    /// Runs nothing if there are no differences to apply.
+    pub(crate) fn pre_apply(&self, differences: usize, problems: usize) -> Result<(), String> {
+        if differences == 0 {
+            return Ok(());
+        }
+        self.run(Hook::PreApply, &scan_vars(differences, problems)).map_err(|e| format!("Aborting, {e}."))
+    }
+
+    pub(crate) fn post_apply(&self, report: &ApplyReport, outcome: Outcome) {
+        let vars = [
+            ("OUTCOME", outcome.description().to_string()),
+            ("EXIT_CODE", outcome.exit_code().to_string()),
+            ("OPERATIONS", report.results.len().to_string()),
+            ("FAILED", report.failed().to_string()),
+            ("BYTES_WRITTEN", report.bytes_written().to_string()),
+            ("SUMMARY", report.summary()),
+        ];
+        self.run_and_warn(Hook::PostApply, &vars);
+    }
+
+    /// Failures of the hooks after the scan and after applying do not change the outcome of the run.
+    fn run_and_warn(&self, hook: Hook, vars: &[(&str, String)]) {
+        if let Err(e) = self.run(hook, vars) {
+            warn!("Warning, {e}");
+        }
+    }
+
+    /// Runs the hook if it is set, fails if it cannot be started or exits with an error.
+    fn run(&self, hook: Hook, vars: &[(&str, String)]) -> Result<(), String> {
+        let Some(command) = self.hooks.command(hook) else { return Ok(()) };
+        info!("Running {} hook: {command}", hook.name());
+        let context = [
+            ("HOOK", hook.name().to_string()),
+            ("MODE", self.mode.name().to_string()),
+            ("SOURCE", self.source_path.to_string()),
+            ("TARGET", self.target_path.to_string()),
+        ];
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(context.iter().chain(vars).map(|(name, value)| (format!("{ENV_PREFIX}{name}"), value)))
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("cannot run the {} hook \"{command}\": {e}", hook.name()))?;
+        for line in String::from_utf8_lossy(&output.stdout).lines().chain(String::from_utf8_lossy(&output.stderr).lines()) {
+            info!("    {line}");
+        }
+        if !output.status.success() {
+            return Err(format!("the {} hook \"{command}\" failed ({})", hook.name(), output.status));
+        }
+        Ok(())
+    }
+}
+
+/// Finds and applies the differences, running the hooks between finding and applying if there are any.
+pub(crate) fn sync_with_hooks(source_path: &str, target_path: &str, options: &SyncOptions, source_listing: &SourceListing, hooks: &RunHooks) -> Result<ApplyReport, String> {
+    if !hooks.between_scan_and_apply() {
+        return apply_during_analysis_with_prints(source_path, target_path, options, source_listing);
+    }
+    apply_after_analysis_with_prints(source_path, target_path, options, source_listing, &mut |differences, problems| {
+        hooks.post_scan(differences, problems);
+        hooks.pre_apply(differences, problems)
+    })
+}
+
+fn scan_vars(differences: usize, problems: usize) -> [(&'static str, String); 2] {
+    [("DIFFERENCES", differences.to_string()), ("PROBLEMS", problems.to_string())]
+}
@@ -0,0 +1,125 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::options::{OutputFormat, SyncOptions};
+use crate::state::state_dir;
+
+/// Name of the log file in the state directory of the target.
+pub(crate) const LOG_FILE_NAME: &str = "log";
+
+/// Writes log messages to the console and, if enabled, appends them with a timestamp to the log file of the target.
+///
+/// Operations are logged at info, details of how files are transferred at debug, failures at error.
+/// Errors and warnings always go to stderr, everything else to stdout, unless stdout is reserved for json.
+struct Logger {
+    console: LevelFilter,
+    stdout: bool,
+    file: Mutex<Option<File>>,
+    file_level: LevelFilter,
+    /// While set, console messages are collected here instead of being printed, e.g. while the terminal ui owns the screen.
+    captured: Mutex<Option<Vec<String>>>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Level of the messages shown on the console: info (warn with json output), lowered by each -q and raised by each -v.
+pub(crate) fn console_level(options: &SyncOptions) -> LevelFilter {
+    let levels = [LevelFilter::Off, LevelFilter::Error, LevelFilter::Warn, LevelFilter::Info, LevelFilter::Debug, LevelFilter::Trace];
+    let default = match options.output_format {
+        OutputFormat::Text => 3,
+        OutputFormat::Json => 2,
+    };
+    levels[(default + options.verbosity as i32).clamp(0, levels.len() as i32 - 1) as usize]
+}
+
+/// Level of the messages written to the log file, which always contains at least all operations.
+pub(crate) fn file_level(options: &SyncOptions) -> LevelFilter {
+    console_level(options).max(LevelFilter::Info)
+}
+
+/// Installs the logger, must be called at most once.
+pub(crate) fn init(options: &SyncOptions) {
+    let logger = LOGGER.get_or_init(|| Logger {
+        console: console_level(options),
+        stdout: options.output_format == OutputFormat::Text,
+        file: Mutex::new(None),
+        file_level: file_level(options),
+        captured: Mutex::new(None),
+    });
+    log::set_logger(logger).expect("logger is initialized only once");
+    log::set_max_level(logger.console.max(logger.file_level));
+}
+
+/// Additionally appends all messages to the log file in the state directory of the target.
+pub(crate) fn log_to_file(target_dir: &str) -> Result<(), String> {
+    let dir = state_dir(target_dir);
+    let path = dir.join(LOG_FILE_NAME);
+    let file = std::fs::create_dir_all(&dir)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+        .map_err(|e| format!("cannot open log file {}: {e}", path.display()))?;
+    if let Some(logger) = LOGGER.get() {
+        *logger.file.lock().unwrap() = Some(file);
+    }
+    Ok(())
+}
+
+/// Stops appending messages to the log file, e.g. between the targets of the watch mode, which each have their own.
+pub(crate) fn close_log_file() {
+    if let Some(logger) = LOGGER.get() {
+        logger.file.lock().unwrap().take();
+    }
+}
+
+/// Starts or stops collecting the console messages instead of printing them, see take_captured.
+pub(crate) fn capture_console(capture: bool) {
+    if let Some(logger) = LOGGER.get() {
+        *logger.captured.lock().unwrap() = capture.then(Vec::new);
+    }
+}
+
+/// The console messages collected since the last call, while capturing.
+pub(crate) fn take_captured() -> Vec<String> {
+    LOGGER.get().and_then(|logger| logger.captured.lock().unwrap().as_mut().map(std::mem::take)).unwrap_or_default()
+}
+
+/// A line of the log file: "2024-01-31T12:00:00Z INFO message", continuation lines of the message are kept as they are.
+pub(crate) fn file_line(time: SystemTime, level: Level, message: &str) -> String {
+    format!("{} {level} {message}\n", humantime::format_rfc3339_seconds(time))
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        //the ui libraries log a lot, of them only warnings and errors are of interest
+        let own = metadata.target().split("::").next() == Some(env!("CARGO_CRATE_NAME"));
+        (own || metadata.level() <= Level::Warn) && (metadata.level() <= self.console || metadata.level() <= self.file_level)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= self.console {
+            if let Some(captured) = self.captured.lock().unwrap().as_mut() {
+                captured.push(record.args().to_string());
+            } else if record.level() <= Level::Warn || !self.stdout {
+                eprintln!("{}", record.args());
+            } else {
+                println!("{}", record.args());
+            }
+        }
+        if record.level() <= self.file_level {
+            if let Some(file) = self.file.lock().unwrap().as_mut() {
+                //a failing log file must not fail the synchronization
+                file.write_all(file_line(SystemTime::now(), record.level(), &record.args().to_string()).as_bytes()).ok();
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            file.flush().ok();
+        }
+    }
+}
@@ -1,101 +1,444 @@
 mod tests;
 mod differences;
 mod ui;
+mod apply;
+mod options;
+mod throttle;
+mod config;
+mod delta;
+mod copy;
+mod hardlinks;
+mod special;
+mod timestamps;
+mod names;
+mod space;
+mod state;
+mod output;
+mod logging;
+mod outcome;
+mod cli;
+mod filters;
+mod versions;
+mod daemon;
+mod hooks;
+mod dialogue;
+mod selection;
+mod tui;
+mod progress;
+#[cfg(target_os = "linux")]
+mod watch;
 
-use std::{env, fs, io};
+use std::{env, fs, io, panic};
+use std::io::Write;
+use std::panic::AssertUnwindSafe;
+use std::collections::HashMap;
 use std::process::exit;
+use log::{error, info, warn};
 use differences::verify_source_fully_newer_than_target;
-use crate::differences::{apply_diffs_source_to_target_with_prints, apply_during_analysis_with_prints};
+use crate::differences::apply_diffs_source_to_target_with_prints;
+use crate::apply::ApplyReport;
+use crate::cli::{Command, Mode, OptionArgs};
+use crate::config::{ConfigFile, Profile};
+use crate::daemon::{run_scheduled, Clock, RunReport, Scheduler, SystemClock};
+use crate::differences::{Difference, SourceListing};
+use crate::hooks::{RunHooks, sync_with_hooks};
+use crate::options::{OutputFormat, SyncOptions};
+use crate::outcome::Outcome;
+use crate::selection::UiProfile;
 use crate::ui::start_synchronization_ui;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    //let args = Vec::from(["path-to-exe".to_string(), "test-env-dirs/source".to_string(), "test-env-dirs/target".to_string(), "cmd".to_string()]);
-    //let args = Vec::from(["path-to-exe".to_string(), "test-env-dirs/source".to_string(), "test-env-dirs/target".to_string(), "ui".to_string()]);
-    //let args = Vec::from(["path-to-exe".to_string(), "test-env-dirs/source".to_string(), "test-env-dirs/target".to_string(), "just-do-it".to_string()]);
-
-    if args.len() == 4 && &args[1] != &args[2] && fs::metadata(&args[1]).is_ok_and(|m| m.is_dir()) && fs::metadata(&args[2]).is_ok_and(|m| m.is_dir()) {
-        println!("Source Path: \"{}\"", args[1]);
-        println!("Target Path: \"{}\"", args[2]);
-        match args[3].as_str() {
-            "ui" => {
-                start_synchronization_ui(args[1].to_string(), args[2].to_string()).expect("cannot fix ui failed so sad");
-                return
+    let cli = cli::try_parse(env::args()).unwrap_or_else(|e| e.exit());
+    let config = config::load_config().unwrap_or_else(|e| invalid_usage(&e));
+    let (mode, source_path, target_paths, profile, args) = match &cli.command {
+        Command::Completions { shell } => {
+            cli::write_completions(*shell);
+            return
+        }
+        Command::Daemon { options: args } => run_daemon(&config, args),
+        Command::Run { profile: name, options: args } => {
+            let Some(profile) = config.profiles.get(name) else {
+                let known: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+                invalid_usage(&format!("unknown profile: \"{name}\" (known profiles: {})", known.join(", ")))
+            };
+            (profile.mode, &profile.source, &profile.targets, Some(profile), args)
+        }
+        command => {
+            let (mode, run) = command.run_args().expect("all other commands synchronize directories");
+            (mode, &run.source, &run.targets, None, &run.options)
+        }
+    };
+    let options = combine_options(&config, profile, args).unwrap_or_else(|e| invalid_usage(&e));
+    prepare_run(source_path, target_paths, &options);
+
+    if mode == Mode::Ui || mode == Mode::Tui {
+        if target_paths.len() > 1 {
+            invalid_usage("The ui synchronizes a single backup directory.");
+        }
+        let options = options_for_target(source_path, &target_paths[0], &options);
+        let profiles = ui_profiles(&config, args);
+        if mode == Mode::Tui {
+            if let Err(e) = tui::start_terminal_ui(source_path.to_string(), target_paths[0].to_string(), options, profiles) {
+                error!("The terminal ui failed: {e}");
+                exit(Outcome::Failed.exit_code())
             }
-            "cmd" => {
-                analyze_and_synchronize_with_dialogue(&args[1], &args[2]);
-                return
+        } else {
+            start_synchronization_ui(source_path.to_string(), target_paths[0].to_string(), options, profiles).expect("cannot fix ui failed so sad");
+        }
+        exit(Outcome::Success.exit_code())
+    }
+
+    if mode == Mode::Watch {
+        exit(watch_targets(source_path, target_paths, &options).exit_code())
+    }
+
+    let source_listing = SourceListing::default();
+    let mut outcomes = Vec::new();
+    for target_path in target_paths {
+        let options = options_for_target(source_path, target_path, &options);
+        //a failing target, even one that panics (e.g. a disconnected drive), does not stop the others
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| synchronize_target(mode, source_path, target_path, &options, &source_listing)))
+            .unwrap_or_else(|_| fail(&options, target_path, &format!("Synchronizing \"{target_path}\" failed unexpectedly.")));
+        outcomes.push((target_path, outcome));
+    }
+    if outcomes.len() > 1 {
+        let lines: Vec<String> = outcomes.iter().map(|(target_path, outcome)| format!("    \"{target_path}\": {}", outcome.description())).collect();
+        info!("Results per backup directory:\n{}", lines.join("\n"));
+    }
+    let outcomes: Vec<Outcome> = outcomes.into_iter().map(|(_, outcome)| outcome).collect();
+    let outcome = match mode {
+        Mode::Verify if outcomes.iter().any(|&o| o != Outcome::Success) => Outcome::Failed,
+        _ => Outcome::combine(&outcomes),
+    };
+    exit(outcome.exit_code())
+}
+
+/// Analyzes or synchronizes a single target, the source listing is shared by all targets of the run.
+fn synchronize_target(mode: Mode, source_path: &str, target_path: &str, options: &SyncOptions, source_listing: &SourceListing) -> Outcome {
+    //runs that do not write to the target neither lock it nor create its state directory
+    let _lock = match mode.writes().then(|| state::TargetLock::acquire(target_path)).transpose() {
+        Ok(lock) => lock,
+        Err(e) => return fail(options, target_path, &e),
+    };
+    let mut options = options.clone();
+    options.resolve_for_target(target_path, mode.writes());
+    let options = &options;
+    let hooks = RunHooks::new(options, mode, source_path, target_path);
+    if let Err(e) = hooks.pre_scan() {
+        let outcome = fail(options, target_path, &e);
+        if mode.writes() {
+            hooks.post_apply(&ApplyReport::default(), outcome);
+        }
+        return outcome;
+    }
+    match mode {
+        Mode::Analyze => {
+            let (diffs, problems) = analyze(source_path, target_path, options, source_listing);
+            hooks.post_scan(diffs.len(), problems.len());
+            let outcome = if diffs.is_empty() { Outcome::NoChanges } else { Outcome::Success };
+            print_analysis_summary(options, target_path, diffs.len(), problems.len(), outcome);
+            outcome
+        }
+        Mode::Verify => {
+            let (diffs, problems) = analyze(source_path, target_path, options, source_listing);
+            hooks.post_scan(diffs.len(), problems.len());
+            let outcome = if diffs.is_empty() { Outcome::Success } else { Outcome::Failed };
+            print_analysis_summary(options, target_path, diffs.len(), problems.len(), outcome);
+            outcome
+        }
+        Mode::Interactive => {
+            let (outcome, report) = analyze_and_synchronize_with_dialogue(source_path, target_path, options, source_listing, &hooks);
+            hooks.post_apply(&report, outcome);
+            outcome
+        }
+        Mode::Sync => {
+            let (outcome, report) = match sync_with_hooks(source_path, target_path, options, source_listing, &hooks) {
+                Ok(report) => (print_summary(options, target_path, &report, None, None, Outcome::of_report(&report)), report),
+                Err(e) => (fail(options, target_path, &format!("{e}\nRefusing to synchronize.")), ApplyReport::default()),
+            };
+            hooks.post_apply(&report, outcome);
+            outcome
+        }
+        Mode::Ui | Mode::Tui | Mode::Watch => unreachable!("the uis and the watch mode are started by main"),
+    }
+}
+
+/// Runs the profiles that have a schedule, until the process is ended.
+fn run_daemon(config: &ConfigFile, args: &OptionArgs) -> ! {
+    let options = combine_options(config, None, args).unwrap_or_else(|e| invalid_usage(&e));
+    let mut scheduled = Vec::new();
+    for (name, profile) in &config.profiles {
+        let Some(schedule) = &profile.schedule else { continue };
+        if profile.mode != Mode::Sync {
+            invalid_usage(&format!("Profile \"{name}\" has a schedule, but scheduled runs always sync (mode = \"sync\")."));
+        }
+        let profile_options = combine_options(config, Some(profile), args).unwrap_or_else(|e| invalid_usage(&format!("invalid profile \"{name}\": {e}")));
+        scheduled.push((name.as_str(), profile, profile_options, schedule.clone()));
+    }
+    if scheduled.is_empty() {
+        invalid_usage("No profile of the config file has a schedule (interval or cron).");
+    }
+
+    logging::init(&options);
+    let schedules = scheduled.iter().map(|(name, _, _, schedule)| (name.to_string(), schedule.clone())).collect();
+    let clock = SystemClock;
+    let mut scheduler = Scheduler::new(schedules, clock.now());
+    run_scheduled(&mut scheduler, &clock, None, &mut |name| {
+        let (name, profile, options, _) = scheduled.iter().find(|(n, ..)| *n == name).expect("only scheduled profiles run");
+        run_scheduled_profile(name, profile, options, &clock)
+    });
+    exit(Outcome::Success.exit_code())
+}
+
+/// Synchronizes each target of the profile and writes a report to it, succeeds if no target failed.
+fn run_scheduled_profile(name: &str, profile: &Profile, options: &SyncOptions, clock: &dyn Clock) -> bool {
+    info!("Running profile \"{name}\"");
+    let source_listing = SourceListing::default();
+    let mut succeeded = true;
+    for target_path in &profile.targets {
+        let started = clock.now();
+        let (outcome, report, error) = scheduled_sync(&profile.source, target_path, options, &source_listing);
+        succeeded &= !matches!(outcome, Outcome::Failed | Outcome::PartialFailure);
+        if let Err(e) = RunReport::new(name, &profile.source, target_path, started..clock.now(), &report, outcome, error).write() {
+            warn!("Warning, cannot write the report of profile \"{name}\" to \"{target_path}\": {e}");
+        }
+    }
+    succeeded
+}
+
+/// Like sync, but a missing directory (e.g. an unmounted drive) only fails this run.
+fn scheduled_sync(source_path: &str, target_path: &str, options: &SyncOptions, source_listing: &SourceListing) -> (Outcome, ApplyReport, Option<String>) {
+    if let Some(dir) = [source_path, target_path].into_iter().find(|dir| !fs::metadata(dir).is_ok_and(|m| m.is_dir())) {
+        let error = format!("Not a directory: \"{dir}\"");
+        return (fail(options, target_path, &error), ApplyReport::default(), Some(error));
+    }
+    let mut options = options_for_target(source_path, target_path, options);
+    let _lock = match state::TargetLock::acquire(target_path) {
+        Ok(lock) => lock,
+        Err(e) => return (fail(&options, target_path, &e), ApplyReport::default(), Some(e)),
+    };
+    options.resolve_for_target(target_path, true);
+    let hooks = RunHooks::new(&options, Mode::Sync, source_path, target_path);
+    if let Err(e) = hooks.pre_scan() {
+        let outcome = fail(&options, target_path, &e);
+        hooks.post_apply(&ApplyReport::default(), outcome);
+        return (outcome, ApplyReport::default(), Some(e));
+    }
+    let result = panic::catch_unwind(AssertUnwindSafe(|| sync_with_hooks(source_path, target_path, &options, source_listing, &hooks)))
+        .unwrap_or_else(|_| Err("Synchronizing failed unexpectedly.".to_string()));
+    let (outcome, report, error) = match result {
+        Ok(report) => (print_summary(&options, target_path, &report, None, None, Outcome::of_report(&report)), report, None),
+        Err(e) => (fail(&options, target_path, &e), ApplyReport::default(), Some(e)),
+    };
+    hooks.post_apply(&report, outcome);
+    (outcome, report, error)
+}
+
+/// Keeps the targets synchronized until the process is ended, targets that are locked are skipped.
+#[cfg(target_os = "linux")]
+fn watch_targets(source_path: &str, target_paths: &[String], options: &SyncOptions) -> Outcome {
+    let mut locks = Vec::new();
+    let mut targets = Vec::new();
+    for target_path in target_paths {
+        let mut target_options = options_for_target(source_path, target_path, options);
+        match state::TargetLock::acquire(target_path) {
+            Ok(lock) => {
+                target_options.resolve_for_target(target_path, true);
+                locks.push(lock);
+                targets.push((target_path.as_str(), target_options));
             }
-            "just-do-it" => {
-                apply_during_analysis_with_prints(&args[1], &args[2]);
-                return
+            Err(e) => {
+                fail(&target_options, target_path, &e);
             }
-            &_ => {}
         }
     }
+    //each target's log file is only written to while it is synchronized
+    logging::close_log_file();
+    if targets.is_empty() {
+        return Outcome::Failed;
+    }
+    watch::watch(source_path, &targets, options)
+}
 
-    println!("Invalid arguments (received {}, expected 2).", args.len() - 1);
-    println!("Excepted argument structure:");
-    println!("[\"DIR[source-path]\", \"DIR[backup-path]\"] ui/cmd/just-do-it");
-    println!("Received argument structure:");
-    println!("{:?}", &args[1..]);
-    println!("\n::HELP::");
-    println!("ui: Will start a UI where each differences to be applies can be selected");
-    println!("cmd: Will start a command line where each differences and problem is shown and a decision can be made to apply or not");
-    println!("just-do-it: Will synchronize the backup directory to the current state of the source directory");
-    println!("Program will NEVER change ANY file in source directory (\"{}\")", if args.len() >= 2 {&args[1]} else {""});
-    println!("Try again. Exiting...");
+#[cfg(not(target_os = "linux"))]
+fn watch_targets(_source_path: &str, _target_paths: &[String], _options: &SyncOptions) -> Outcome {
+    invalid_usage("The watch mode requires inotify, which is only available on Linux.")
 }
 
-fn analyze_and_synchronize_with_dialogue(source_path: &String, target_path: &String) {
-    println!("Will now analyse directories and verify that backup directory does not contain any files that\n    \
+/// Options of a run: the defaults, overridden by the config file, the profile and the command line, in that order.
+fn combine_options(config: &ConfigFile, profile: Option<&Profile>, args: &OptionArgs) -> Result<SyncOptions, String> {
+    let mut options = SyncOptions::default();
+    config.apply_to(&mut options)?;
+    if let Some(profile) = profile {
+        profile.options.apply_to(&mut options)?;
+    }
+    args.apply_to(&mut options);
+    Ok(options)
+}
+
+/// The profiles the ui offers, with the options given on the command line.
+fn ui_profiles(config: &ConfigFile, args: &OptionArgs) -> Vec<UiProfile> {
+    let mut profiles = Vec::new();
+    for (name, profile) in &config.profiles {
+        let options = match combine_options(config, Some(profile), args) {
+            Ok(options) => options,
+            Err(e) => {
+                warn!("Warning, skipping profile \"{name}\": {e}");
+                continue
+            }
+        };
+        //the ui synchronizes a single target, so profiles with several are offered once per target
+        for target_path in &profile.targets {
+            let name = if profile.targets.len() == 1 { name.clone() } else { format!("{name}: {target_path}") };
+            profiles.push(UiProfile { name, source_path: profile.source.clone(), target_path: target_path.clone(), options: options.clone() });
+        }
+    }
+    profiles
+}
+
+fn invalid_usage(message: &str) -> ! {
+    eprintln!("{message}");
+    exit(Outcome::InvalidUsage.exit_code())
+}
+
+/// Checks the directories and sets up logging.
+/// Exits if the directories are invalid.
+fn prepare_run(source_path: &str, target_paths: &[String], options: &SyncOptions) {
+    for dir in std::iter::once(source_path).chain(target_paths.iter().map(String::as_str)) {
+        if !fs::metadata(dir).is_ok_and(|m| m.is_dir()) {
+            invalid_usage(&format!("Not a directory: \"{dir}\""));
+        }
+    }
+    for (i, target_path) in target_paths.iter().enumerate() {
+        if target_path == source_path {
+            invalid_usage(&format!("Source and backup directory must differ: \"{source_path}\""));
+        }
+        if target_paths[..i].contains(target_path) {
+            invalid_usage(&format!("Backup directory given twice: \"{target_path}\""));
+        }
+    }
+    logging::init(options);
+}
+
+/// Options of a single target: logs to its log file.
+/// The options that depend on the target filesystem are resolved once the lock on the target is held.
+fn options_for_target(source_path: &str, target_path: &str, options: &SyncOptions) -> SyncOptions {
+    let options = options.clone();
+    if options.log_file {
+        if let Err(e) = logging::log_to_file(target_path) {
+            warn!("Warning, {e}");
+        }
+    }
+    info!("Source Path: \"{source_path}\"");
+    info!("Target Path: \"{target_path}\"");
+    options
+}
+
+/// Ends a run that applied differences with a summary of the executed operations, naming the target (there may be several).
+fn print_summary(options: &SyncOptions, target_path: &str, report: &ApplyReport, differences: Option<usize>, problems: Option<usize>, outcome: Outcome) -> Outcome {
+    match options.output_format {
+        OutputFormat::Text => info!("Backup directory \"{target_path}\": {}", report.summary()),
+        OutputFormat::Json => output::summary_record(target_path, report, differences, problems, outcome).emit(),
+    }
+    outcome
+}
+
+/// Messages for the user go to stdout, unless stdout is reserved for json.
+fn say(options: &SyncOptions, message: &str) {
+    match options.output_format {
+        OutputFormat::Text => println!("{message}"),
+        OutputFormat::Json => eprintln!("{message}"),
+    }
+}
+
+/// Reports that the run could not be started or completed for the target, the other targets are still synchronized.
+fn fail(options: &SyncOptions, target_path: &str, message: &str) -> Outcome {
+    if options.output_format == OutputFormat::Json {
+        output::Record::Error { target: Some(target_path), message }.emit();
+    }
+    error!("{message}\nSkipping backup directory \"{target_path}\".");
+    log::logger().flush();
+    Outcome::Failed
+}
+
+/// Finds all differences and problems and shows them.
+fn analyze(source_path: &str, target_path: &str, options: &SyncOptions, source_listing: &SourceListing) -> (Vec<Difference>, HashMap<Difference, String>) {
+    info!("Will now analyse directories and verify that backup directory does not contain any files that\n    \
               are newer than their expression in the source and\n    \
               that backup directory does not contain any files that don't exist in source,\n    \
               but are newer than the last common modification date (assumed time of last synchronization).");
 
-    let diffs = differences::find_differences(&source_path, &target_path);
-    if diffs.is_empty() {
-        println!("Found NO differences. Backup is up-to-date.");
-        exit(0);
-    }
-    let problems = verify_source_fully_newer_than_target(&diffs);
-    println!("Differences:");
-    for d in &diffs {
-        println!("{}", d.describe());
-        println!("\n    in directory: {}", d.get_directory_path(source_path.len(), target_path.len()));
-        match problems.get(d) {
-            None => {}
-            Some(desc) => {
-                println!("\n    Problem: {desc}");
+    let diffs = differences::find_differences_with(source_listing, source_path, target_path, options);
+    let problems = verify_source_fully_newer_than_target(&diffs, options);
+    if options.output_format == OutputFormat::Json {
+        for d in &diffs {
+            output::difference_record(d, problems.get(d).map(String::as_str)).emit();
+        }
+    } else if !diffs.is_empty() {
+        println!("Differences:");
+        for d in &diffs {
+            println!("{}", d.describe());
+            println!("\n    in directory: {}", d.get_directory_path(source_path.len(), target_path.len()));
+            match problems.get(d) {
+                None => {}
+                Some(desc) => {
+                    println!("\n    Problem: {desc}");
+                }
             }
         }
     }
+    (diffs, problems)
+}
 
-    if !&problems.is_empty() {
-        println!("Problems found (see above).\n    \
-            Please study the problems carefully and decide how to proceed.
-            To simply override ALL changes in the backup directory,\n    \
-            please type \"continue\".    \
-            If you type anything else, the program will exit.");
-    } else {
-        println!("{} differences found (see above).\n    \
-            0 Problems were detected, but there is no guarantee that this is correct.\n    \
-            Please study the differences in detail and choose whether you want to continue.\n    \
-            To proceed please type \"continue\".    \
-            If you type anything else, the program will exit.", diffs.len());
+/// Ends a run that only analyzed.
+fn print_analysis_summary(options: &SyncOptions, target_path: &str, differences: usize, problems: usize, outcome: Outcome) {
+    match options.output_format {
+        OutputFormat::Text if differences == 0 => info!("Found NO differences. Backup is up-to-date."),
+        OutputFormat::Text => info!("{differences} differences found, {problems} of them with problems."),
+        OutputFormat::Json => output::summary_record(target_path, &ApplyReport::default(), Some(differences), Some(problems), outcome).emit(),
     }
-    let mut s = String::new();
-    io::stdin().read_line(&mut s).expect("stdio error");
-    match s.trim() {
-        "continue" => {},
-        _ => {
-            println!("Ok. Exiting...");
-            exit(0)
-        }
+}
+
+/// Returns the outcome and the report of the applied differences.
+fn analyze_and_synchronize_with_dialogue(source_path: &str, target_path: &str, options: &SyncOptions, source_listing: &SourceListing, hooks: &RunHooks) -> (Outcome, ApplyReport) {
+    let (diffs, problems) = analyze(source_path, target_path, options, source_listing);
+    hooks.post_scan(diffs.len(), problems.len());
+    if diffs.is_empty() {
+        print_analysis_summary(options, target_path, 0, 0, Outcome::NoChanges);
+        return (Outcome::NoChanges, ApplyReport::default());
+    }
+
+    say(options, &format!("{} differences found (see above), {} of them with problems.\n    \
+        There is no guarantee that all problems were detected, please study the differences in detail.\n    \
+        Decide for each difference whether it is applied to the backup directory, nothing is applied before all are decided.",
+        diffs.len(), problems.len()));
+    let mut prompts: Box<dyn Write> = match options.output_format {
+        OutputFormat::Text => Box::new(io::stdout()),
+        OutputFormat::Json => Box::new(io::stderr()),
+    };
+    let Some(decisions) = dialogue::decide_each(&diffs, &problems, source_path, target_path, &mut io::stdin().lock(), &mut prompts) else {
+        say(options, "Ok. Exiting...");
+        let outcome = if problems.is_empty() { Outcome::Aborted } else { Outcome::ProblemsNotApplied };
+        return (outcome, ApplyReport::default());
+    };
+    let selected: Vec<&Difference> = diffs.iter().zip(decisions).filter(|(_, apply)| *apply).map(|(d, _)| d).collect();
+    let selected_problems = selected.iter().filter(|d| problems.contains_key(d)).count();
+    if selected.is_empty() {
+        say(options, "Nothing selected. Exiting...");
+        let outcome = if problems.is_empty() { Outcome::Aborted } else { Outcome::ProblemsNotApplied };
+        return (outcome, ApplyReport::default());
+    }
+
+    if let Err(e) = space::check_free_space(target_path, selected.iter().copied(), options) {
+        return (fail(options, target_path, &format!("{e}\nRefusing to synchronize.")), ApplyReport::default());
+    }
+    if let Err(e) = hooks.pre_apply(selected.len(), selected_problems) {
+        return (fail(options, target_path, &format!("{e}\nRefusing to synchronize.")), ApplyReport::default());
     }
 
-    println!("Found {} differences. Overriding all in backup directory.", &diffs.len());
+    info!("Applying {} of {} differences to the backup directory.", selected.len(), diffs.len());
 
-    apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter());
+    let report = apply_diffs_source_to_target_with_prints(source_path, target_path, selected.into_iter(), options);
+    let outcome = Outcome::of_selection(&report, problems.len() - selected_problems);
+    (print_summary(options, target_path, &report, Some(diffs.len()), Some(problems.len()), outcome), report)
 }
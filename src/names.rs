@@ -0,0 +1,36 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+/// How the names of files in source and target are matched to each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct NameMatching {
+    /// Names that only differ in case denote the same file (the target filesystem is case-insensitive).
+    pub(crate) case_insensitive: bool,
+    /// Names are compared in Unicode normalization form C, so that e.g. decomposed names from macOS (NFD)
+    /// match the composed names usually created on Linux.
+    pub(crate) normalize_unicode: bool,
+}
+
+impl NameMatching {
+    /// Names with equal keys denote the same file in the target.
+    pub(crate) fn key(&self, name: &str) -> String {
+        let name = if self.normalize_unicode { name.nfc().collect() } else { name.to_string() };
+        if self.case_insensitive {
+            name.to_lowercase()
+        } else {
+            name
+        }
+    }
+}
+
+/// Whether the filesystem the directory is on ignores case (FAT, exFAT, default NTFS/APFS, casefolded ext4),
+/// determined by creating a file and looking it up with a differently cased name.
+pub(crate) fn probe_case_insensitive(dir: &Path) -> io::Result<bool> {
+    let probe = dir.join(".directory_synchronizer-case-probe");
+    fs::write(&probe, [])?;
+    let insensitive = fs::symlink_metadata(dir.join(".DIRECTORY_SYNCHRONIZER-CASE-PROBE")).is_ok();
+    fs::remove_file(&probe)?;
+    Ok(insensitive)
+}
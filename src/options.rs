@@ -0,0 +1,337 @@
+use std::sync::Arc;
+use std::time::Duration;
+use log::{info, warn};
+use crate::filters::Excludes;
+use crate::hooks::Hooks;
+use crate::names::{NameMatching, probe_case_insensitive};
+use crate::progress::Progress;
+use crate::state::{recall_probed, record_probed, state_dir};
+use crate::timestamps::probe_mtime_granularity;
+
+/// Settings that influence how differences are found and applied.
+#[derive(Debug, Clone)]
+pub(crate) struct SyncOptions {
+    /// Number of threads copying and removing files in parallel while applying differences.
+    pub(crate) workers: usize,
+    /// Upper limit of bytes written per second while copying, shared by all workers.
+    pub(crate) max_bytes_per_second: Option<u64>,
+    /// Upper limit of files copied, removed or created per second, shared by all workers.
+    pub(crate) max_files_per_second: Option<f64>,
+    /// How files that were moved within the source are recognized, so they can be moved in the target as well.
+    pub(crate) move_detection: MoveDetection,
+    /// Replaced files at least this large (on both sides) are updated by rewriting only the blocks that changed.
+    pub(crate) delta_min_size: Option<u64>,
+    /// What happens to FIFOs, sockets and device nodes in the source.
+    pub(crate) special_files: SpecialFilePolicy,
+    /// Directories on another filesystem than the source or target root (mount points) are skipped.
+    pub(crate) one_file_system: bool,
+    /// How much modification times of source and target may differ and still be considered equal.
+    pub(crate) mtime_tolerance: MtimeTolerance,
+    /// Whether names that only differ in case denote the same file in the target.
+    pub(crate) case_sensitivity: CaseSensitivity,
+    /// Whether names that are equal after Unicode normalization denote the same file.
+    pub(crate) normalize_unicode: bool,
+    /// Bytes that have to remain free in the target after applying, None disables the check.
+    pub(crate) free_space_margin: Option<u64>,
+    /// Whether results are written as text or as json (see output::Record).
+    pub(crate) output_format: OutputFormat,
+    /// Raises (-v) or lowers (-q) the level of the messages shown on the console, 0 is the default level.
+    pub(crate) verbosity: i8,
+    /// Whether all messages are also appended to the log file in the state directory of the target.
+    pub(crate) log_file: bool,
+    /// Paths that are neither compared nor synchronized.
+    pub(crate) excludes: Excludes,
+    /// How files present on both sides are compared.
+    pub(crate) comparison: Comparison,
+    /// Number of runs whose replaced and deleted files are kept in the state directory of the target, None deletes them right away.
+    pub(crate) retention: Option<usize>,
+    /// How long the watch mode waits for further changes before applying those it has seen.
+    pub(crate) debounce: Duration,
+    /// How often the watch mode compares the full trees, to catch changes it missed. None disables it.
+    pub(crate) reconcile_interval: Option<Duration>,
+    /// Commands run before and after analyzing and applying.
+    pub(crate) hooks: Hooks,
+    /// Counts the scanned directories and executed operations of a run in the background, which it cancels.
+    pub(crate) progress: Option<Arc<Progress>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MoveDetection {
+    /// Moved files are deleted and copied again.
+    Off,
+    /// A deleted and a new file with equal size and modification time are assumed to be the same file,
+    /// a deleted and a new directory if all their entries have equal relative paths, sizes and modification times.
+    SizeAndTime,
+    /// Like SizeAndTime, but the file contents are additionally compared.
+    Hash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SpecialFilePolicy {
+    /// Special files are not synchronized, a warning is printed.
+    Skip,
+    /// Special files are created in the target with mknod (device nodes usually require privileges).
+    Recreate,
+    /// Special files are reported as problems and fail to be applied.
+    Error,
+}
+
+impl SpecialFilePolicy {
+    pub(crate) fn parse(name: &str, value: &str) -> Result<SpecialFilePolicy, String> {
+        match value {
+            "skip" => Ok(SpecialFilePolicy::Skip),
+            "recreate" => Ok(SpecialFilePolicy::Recreate),
+            "error" => Ok(SpecialFilePolicy::Error),
+            _ => Err(format!("invalid value for {name}: \"{value}\" (expected skip, recreate or error)")),
+        }
+    }
+}
+
+impl MoveDetection {
+    pub(crate) fn parse(name: &str, value: &str) -> Result<MoveDetection, String> {
+        match value {
+            "off" => Ok(MoveDetection::Off),
+            "size-time" => Ok(MoveDetection::SizeAndTime),
+            "hash" => Ok(MoveDetection::Hash),
+            _ => Err(format!("invalid value for {name}: \"{value}\" (expected off, size-time or hash)")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MtimeTolerance {
+    Fixed(Duration),
+    /// The timestamp granularity of the target filesystem, determined by writing a test file (e.g. 2s on FAT/exFAT).
+    Auto,
+}
+
+impl MtimeTolerance {
+    /// Parses "auto" or a duration such as "2s", "500ms" or "2" (seconds).
+    pub(crate) fn parse(name: &str, value: &str) -> Result<MtimeTolerance, String> {
+        let invalid = || format!("invalid value for {name}: \"{value}\" (expected auto or a duration like 2s or 500ms)");
+        if value == "auto" {
+            return Ok(MtimeTolerance::Auto);
+        }
+        let (digits, factor_ms) = if let Some(ms) = value.strip_suffix("ms") {
+            (ms, 1)
+        } else {
+            (value.strip_suffix('s').unwrap_or(value), 1000)
+        };
+        let number = digits.parse::<u64>().map_err(|_| invalid())?;
+        number.checked_mul(factor_ms).map(|ms| MtimeTolerance::Fixed(Duration::from_millis(ms))).ok_or_else(invalid)
+    }
+
+    /// The tolerance to use, Auto is exact until it was resolved with `SyncOptions::resolve_mtime_tolerance`.
+    pub(crate) fn duration(&self) -> Duration {
+        match self {
+            MtimeTolerance::Fixed(tolerance) => *tolerance,
+            MtimeTolerance::Auto => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CaseSensitivity {
+    Sensitive,
+    Insensitive,
+    /// Determined by probing the target directory.
+    Auto,
+}
+
+impl CaseSensitivity {
+    pub(crate) fn parse(name: &str, value: &str) -> Result<CaseSensitivity, String> {
+        match value {
+            "sensitive" => Ok(CaseSensitivity::Sensitive),
+            "insensitive" => Ok(CaseSensitivity::Insensitive),
+            "auto" => Ok(CaseSensitivity::Auto),
+            _ => Err(format!("invalid value for {name}: \"{value}\" (expected auto, sensitive or insensitive)")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    Text,
+    /// Newline-delimited json objects on stdout, everything else on stderr.
+    Json,
+}
+
+impl OutputFormat {
+    pub(crate) fn parse(name: &str, value: &str) -> Result<OutputFormat, String> {
+        match value {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("invalid value for {name}: \"{value}\" (expected text or json)")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Comparison {
+    /// Files differ if their modification times differ (beyond the mtime tolerance).
+    Mtime,
+    /// Files differ if their sizes or contents differ, modification times are ignored.
+    Checksum,
+}
+
+impl Comparison {
+    pub(crate) fn parse(name: &str, value: &str) -> Result<Comparison, String> {
+        match value {
+            "mtime" => Ok(Comparison::Mtime),
+            "checksum" => Ok(Comparison::Checksum),
+            _ => Err(format!("invalid value for {name}: \"{value}\" (expected mtime or checksum)")),
+        }
+    }
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            workers: std::thread::available_parallelism().map(|n| n.get().min(8)).unwrap_or(1),
+            max_bytes_per_second: None,
+            max_files_per_second: None,
+            move_detection: MoveDetection::Off,
+            delta_min_size: None,
+            special_files: SpecialFilePolicy::Skip,
+            one_file_system: false,
+            mtime_tolerance: MtimeTolerance::Fixed(Duration::ZERO),
+            case_sensitivity: CaseSensitivity::Auto,
+            normalize_unicode: false,
+            free_space_margin: None,
+            output_format: OutputFormat::Text,
+            verbosity: 0,
+            log_file: false,
+            excludes: Excludes::default(),
+            comparison: Comparison::Mtime,
+            retention: None,
+            debounce: Duration::from_secs(2),
+            reconcile_interval: Some(Duration::from_secs(60 * 60)),
+            hooks: Hooks::default(),
+            progress: None,
+        }
+    }
+}
+
+/// Names of the probed properties recorded in the state directory.
+const MTIME_GRANULARITY: &str = "mtime_granularity_ns";
+const CASE_INSENSITIVE: &str = "case_insensitive";
+const NOT_PROBED: &str = "not probed yet, only runs that write to the backup directory probe it";
+
+impl SyncOptions {
+    /// Resolves all options that depend on properties of the target filesystem.
+    /// Probing creates files in the state directory of the target, so the lock on the target has to be held.
+    /// Runs that do not write to the target (`probe` false) use the properties recorded by the last run that probed them.
+    pub(crate) fn resolve_for_target(&mut self, target_dir: &str, probe: bool) {
+        self.resolve_mtime_tolerance(target_dir, probe);
+        self.resolve_case_sensitivity(target_dir, probe);
+    }
+
+    /// Replaces an automatic mtime tolerance with the timestamp granularity probed in the target directory.
+    pub(crate) fn resolve_mtime_tolerance(&mut self, target_dir: &str, probe: bool) {
+        if self.mtime_tolerance == MtimeTolerance::Auto {
+            let granularity = match probe {
+                true => probe_mtime_granularity(&state_dir(target_dir)).map_err(|e| e.to_string())
+                    .inspect(|granularity| remember_probed(target_dir, MTIME_GRANULARITY, &granularity.as_nanos().to_string())),
+                false => recall_probed(target_dir, MTIME_GRANULARITY).and_then(|nanos| nanos.parse().ok()).map(Duration::from_nanos)
+                    .ok_or_else(|| NOT_PROBED.to_string()),
+            };
+            let tolerance = match granularity {
+                Ok(granularity) => {
+                    info!("Detected timestamp granularity of backup directory: {granularity:?}");
+                    granularity
+                }
+                Err(e) => {
+                    warn!("Warning, could not detect timestamp granularity of backup directory, comparing exactly: {e}");
+                    Duration::ZERO
+                }
+            };
+            self.mtime_tolerance = MtimeTolerance::Fixed(tolerance);
+        }
+    }
+
+    /// Replaces an automatic case sensitivity with the one probed in the target directory.
+    pub(crate) fn resolve_case_sensitivity(&mut self, target_dir: &str, probe: bool) {
+        if self.case_sensitivity == CaseSensitivity::Auto {
+            let insensitive = match probe {
+                true => probe_case_insensitive(&state_dir(target_dir)).map_err(|e| e.to_string())
+                    .inspect(|insensitive| remember_probed(target_dir, CASE_INSENSITIVE, &insensitive.to_string())),
+                false => recall_probed(target_dir, CASE_INSENSITIVE).and_then(|insensitive| insensitive.parse().ok())
+                    .ok_or_else(|| NOT_PROBED.to_string()),
+            };
+            self.case_sensitivity = match insensitive {
+                Ok(true) => {
+                    info!("Detected case-insensitive backup directory");
+                    CaseSensitivity::Insensitive
+                }
+                Ok(false) => CaseSensitivity::Sensitive,
+                Err(e) => {
+                    warn!("Warning, could not detect case sensitivity of backup directory, assuming case-sensitive: {e}");
+                    CaseSensitivity::Sensitive
+                }
+            };
+        }
+    }
+
+    /// How names in source and target are matched, Auto case sensitivity is sensitive until resolved.
+    pub(crate) fn name_matching(&self) -> NameMatching {
+        NameMatching { case_insensitive: self.case_sensitivity == CaseSensitivity::Insensitive, normalize_unicode: self.normalize_unicode }
+    }
+}
+
+fn parse_number(name: &str, value: &str) -> Result<u64, String> {
+    value.parse().map_err(|_| format!("invalid value for {name}: \"{value}\""))
+}
+
+/// Parses a byte count with an optional binary suffix (K, M, G), e.g. "512K" or "10M".
+pub(crate) fn parse_size(name: &str, value: &str) -> Result<u64, String> {
+    let trimmed = value.trim_end_matches(['B', 'b', 'i']);
+    let (digits, factor) = match trimmed.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&trimmed[..i], 1024),
+        Some((i, 'M' | 'm')) => (&trimmed[..i], 1024 * 1024),
+        Some((i, 'G' | 'g')) => (&trimmed[..i], 1024 * 1024 * 1024),
+        _ => (trimmed, 1),
+    };
+    parse_number(name, digits)?.checked_mul(factor).ok_or_else(|| format!("invalid value for {name}: \"{value}\""))
+}
+
+/// Parses a number of runs, "off" or 0 disable the retention.
+pub(crate) fn parse_retention(name: &str, value: &str) -> Result<Option<usize>, String> {
+    if value == "off" {
+        return Ok(None);
+    }
+    Ok(Some(parse_number(name, value)? as usize).filter(|&runs| runs > 0))
+}
+
+/// Parses a size like parse_size, or "off".
+pub(crate) fn parse_optional_size(name: &str, value: &str) -> Result<Option<u64>, String> {
+    if value == "off" {
+        return Ok(None);
+    }
+    parse_size(name, value).map(Some)
+}
+
+/// Parses a duration such as "2s", "500ms" or "1h 30m".
+pub(crate) fn parse_duration(name: &str, value: &str) -> Result<Duration, String> {
+    humantime::parse_duration(value).map_err(|e| format!("invalid value for {name}: \"{value}\" ({e})"))
+}
+
+/// Parses a duration like parse_duration, "off" or 0 disable it.
+pub(crate) fn parse_optional_duration(name: &str, value: &str) -> Result<Option<Duration>, String> {
+    if value == "off" {
+        return Ok(None);
+    }
+    parse_duration(name, value).map(|duration| Some(duration).filter(|d| !d.is_zero()))
+}
+
+pub(crate) fn parse_rate(name: &str, value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate > 0.0 => Ok(rate),
+        _ => Err(format!("invalid value for {name}: \"{value}\"")),
+    }
+}
+
+fn remember_probed(target_dir: &str, name: &str, value: &str) {
+    if let Err(e) = record_probed(target_dir, name, value) {
+        warn!("Warning, cannot record the probed {name} of the backup directory: {e}");
+    }
+}
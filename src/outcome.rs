@@ -0,0 +1,78 @@
+use crate::apply::ApplyReport;
+
+/// How a run ended, reported as the exit code of the process, so that scripts and cron can act on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// All differences were applied.
+    Success,
+    /// The run could not be started or completed, e.g. because the target is locked or too full.
+    Failed,
+    /// The arguments or the config file are invalid.
+    InvalidUsage,
+    /// Source and target were already equal.
+    NoChanges,
+    /// Some operations failed, the others were applied.
+    PartialFailure,
+    /// Problems were found and the user chose not to apply (some of) them.
+    ProblemsNotApplied,
+    /// The user chose not to apply the differences.
+    Aborted,
+}
+
+impl Outcome {
+    pub(crate) fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::Failed => 1,
+            Outcome::InvalidUsage => 2,
+            Outcome::NoChanges => 3,
+            Outcome::PartialFailure => 4,
+            Outcome::ProblemsNotApplied => 5,
+            Outcome::Aborted => 6,
+        }
+    }
+
+    pub(crate) fn description(self) -> &'static str {
+        match self {
+            Outcome::Success => "all differences applied",
+            Outcome::Failed => "failed",
+            Outcome::InvalidUsage => "invalid usage",
+            Outcome::NoChanges => "no differences",
+            Outcome::PartialFailure => "some operations failed",
+            Outcome::ProblemsNotApplied => "problems found and not applied",
+            Outcome::Aborted => "aborted by the user",
+        }
+    }
+
+    /// Outcome of a run with several targets: the outcome of all targets, if they agree.
+    /// Otherwise failures are partial failures, since other targets succeeded, then declined problems and aborts take precedence.
+    pub(crate) fn combine(outcomes: &[Outcome]) -> Outcome {
+        match outcomes {
+            [] => Outcome::NoChanges,
+            [first, rest @ ..] if rest.iter().all(|o| o == first) => *first,
+            _ => [Outcome::Failed, Outcome::PartialFailure, Outcome::ProblemsNotApplied, Outcome::Aborted].into_iter()
+                .find(|o| outcomes.contains(o))
+                .map(|o| if o == Outcome::Failed { Outcome::PartialFailure } else { o })
+                .unwrap_or(Outcome::Success),
+        }
+    }
+
+    /// Outcome of applying the differences the user selected, problems left unapplied are reported unless operations failed.
+    pub(crate) fn of_selection(report: &ApplyReport, problems_not_applied: usize) -> Outcome {
+        match Outcome::of_report(report) {
+            Outcome::Success | Outcome::NoChanges if problems_not_applied > 0 => Outcome::ProblemsNotApplied,
+            outcome => outcome,
+        }
+    }
+
+    /// Outcome of applying, a report without operations means nothing had to be changed.
+    pub(crate) fn of_report(report: &ApplyReport) -> Outcome {
+        if report.failed() > 0 {
+            Outcome::PartialFailure
+        } else if report.results.is_empty() {
+            Outcome::NoChanges
+        } else {
+            Outcome::Success
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+use crate::apply::{ApplyObserver, ApplyReport, Operation, OperationCounts, OperationResult, LoggingObserver};
+use crate::differences::Difference;
+use crate::hardlinks::HardlinkChange;
+use crate::options::{OutputFormat, SyncOptions};
+use crate::outcome::Outcome;
+
+/// Machine-readable output (`--format json`) of analyzing and applying.
+///
+/// The output is newline-delimited JSON, one object per line, distinguished by "type".
+/// Warnings and prompts are written to stderr, so that stdout only contains these objects.
+/// Paths are as found (source or backup directory prefixed), `null` where not applicable.
+///
+/// ```text
+/// {"type":"difference","kind":"new|deleted|modified|moved|hardlinked|unlinked|collision",
+///  "entry":"file|dir|fifo|socket|chardev|blockdev","path":"relative/path",
+///  "source":"...","target":"...","moved_from":"...","problem":"..."}
+/// {"type":"operation","operation":"create_dir|copy_file|remove_file|remove_dir|move_file|hard_link|create_special|refuse_special",
+///  "from":"...","to":"...","bytes":0,"method":"reflink|copy_file_range|delta|plain","error":"..."}
+/// {"type":"summary","target":"...","differences":0,"problems":0,"operations":0,"failed":0,"bytes_written":0,
+///  "by_operation":{"copy_file":{"count":0,"failed":0,"bytes":0},...},"exit_code":0}
+/// {"type":"error","target":"...","message":"..."}
+/// ```
+/// With several backup directories, the records of each follow each other, ending with its summary.
+/// cmd emits all differences with their problems before asking to continue, then the operations and the summary.
+/// just-do-it streams differences (without problems, if they are applied while scanning) and operations as they happen.
+/// In the summary, "differences" and "problems" are `null` if they were not determined,
+/// "by_operation" only contains the operations that were executed and "exit_code" is the exit code of the process (see outcome::Outcome),
+/// with several backup directories the one this directory alone would have caused.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Record<'a> {
+    Difference {
+        kind: &'static str,
+        entry: String,
+        path: &'a str,
+        source: Option<&'a str>,
+        target: Option<&'a str>,
+        moved_from: Option<&'a str>,
+        problem: Option<&'a str>,
+    },
+    Operation {
+        operation: &'static str,
+        from: Option<&'a str>,
+        to: Option<&'a str>,
+        bytes: u64,
+        method: Option<String>,
+        error: Option<&'a str>,
+    },
+    Summary {
+        target: &'a str,
+        differences: Option<usize>,
+        problems: Option<usize>,
+        operations: usize,
+        failed: usize,
+        bytes_written: u64,
+        by_operation: BTreeMap<&'static str, OperationCounts>,
+        exit_code: i32,
+    },
+    Error {
+        target: Option<&'a str>,
+        message: &'a str,
+    },
+}
+
+impl Record<'_> {
+    pub(crate) fn emit(&self) {
+        println!("{}", serde_json::to_string(self).expect("records are always serializable"));
+    }
+}
+
+pub(crate) fn difference_record<'a>(d: &'a Difference, problem: Option<&'a str>) -> Record<'a> {
+    let kind = if d.collides_with.is_some() {
+        "collision"
+    } else if d.p_moved_from.is_some() {
+        "moved"
+    } else {
+        match (&d.p_source, &d.p_target, &d.hardlink) {
+            (_, _, Some(HardlinkChange::Link { .. })) => "hardlinked",
+            (_, _, Some(HardlinkChange::Unlink)) => "unlinked",
+            (Some(_), Some(_), None) => "modified",
+            (Some(_), None, None) => "new",
+            _ => "deleted",
+        }
+    };
+    let annotated = d.p_source.as_ref().or(d.p_target.as_ref()).expect("both are none, never happens, bug");
+    Record::Difference {
+        kind,
+        entry: d.type_marker().to_lowercase(),
+        path: annotated.relative_path(),
+        source: d.p_source.as_ref().map(|p| p.path.as_str()),
+        target: d.p_target.as_ref().map(|p| p.path.as_str()),
+        moved_from: d.p_moved_from.as_ref().map(|p| p.path.as_str()),
+        problem,
+    }
+}
+
+pub(crate) fn operation_record(result: &OperationResult) -> Record<'_> {
+    let (from, to) = match &result.operation {
+        Operation::CreateDir { from, to } => (Some(from), Some(to)),
+        Operation::CopyFile { from, to, .. } => (Some(from), Some(to)),
+        Operation::RemoveFile { path } => (None, Some(path)),
+        Operation::RemoveDir { path } => (None, Some(path)),
+        Operation::MoveFile { from, to } => (Some(from), Some(to)),
+        Operation::HardLink { existing, to, .. } => (Some(existing), Some(to)),
+        Operation::CreateSpecial { from, to, .. } => (Some(from), Some(to)),
+        Operation::RefuseSpecial { path, .. } => (Some(path), None),
+    };
+    Record::Operation {
+        operation: result.operation.name(),
+        from: from.map(String::as_str),
+        to: to.map(String::as_str),
+        bytes: result.bytes,
+        method: result.method.map(|m| m.to_string()),
+        error: result.error.as_deref(),
+    }
+}
+
+pub(crate) fn summary_record<'a>(target: &'a str, report: &ApplyReport, differences: Option<usize>, problems: Option<usize>, outcome: Outcome) -> Record<'a> {
+    Record::Summary {
+        target,
+        differences,
+        problems,
+        operations: report.results.len(),
+        failed: report.failed(),
+        bytes_written: report.bytes_written(),
+        by_operation: report.by_operation(),
+        exit_code: outcome.exit_code(),
+    }
+}
+
+pub(crate) struct JsonObserver;
+
+impl ApplyObserver for JsonObserver {
+    fn started(&self, operation: &Operation) {
+        LoggingObserver.started(operation);
+    }
+    fn finished(&self, result: &OperationResult) {
+        operation_record(result).emit();
+        LoggingObserver.finished(result);
+    }
+    fn difference_found(&self, difference: &Difference, problem: Option<&str>) {
+        difference_record(difference, problem).emit();
+    }
+}
+
+/// The observer that writes the progress of applying in the configured output format.
+/// Operations are logged in both formats, so that the log file is complete.
+pub(crate) fn observer(options: &SyncOptions) -> &'static dyn ApplyObserver {
+    match options.output_format {
+        OutputFormat::Text => &LoggingObserver,
+        OutputFormat::Json => &JsonObserver,
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Progress of an analysis or application running in the background, followed and possibly cancelled by the ui.
+///
+/// Scanning counts the directories it compared, applying the operations it planned and executed.
+/// Once cancelled, scanning stops before the next directory and applying before the next operation,
+/// an operation that already started (e.g. copying a large file) is completed.
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    directories_scanned: AtomicUsize,
+    operations_planned: AtomicUsize,
+    operations_executed: AtomicUsize,
+    bytes_written: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+/// The counts of a Progress at one point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ProgressSnapshot {
+    pub(crate) directories_scanned: usize,
+    pub(crate) operations_planned: usize,
+    pub(crate) operations_executed: usize,
+    pub(crate) bytes_written: u64,
+}
+
+impl Progress {
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn directory_scanned(&self) {
+        self.directories_scanned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn operations_planned(&self, count: usize) {
+        self.operations_planned.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn operation_executed(&self, bytes: u64) {
+        self.operations_executed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            directories_scanned: self.directories_scanned.load(Ordering::Relaxed),
+            operations_planned: self.operations_planned.load(Ordering::Relaxed),
+            operations_executed: self.operations_executed.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ProgressSnapshot {
+    /// Share of the planned operations that were executed, None while nothing is planned (e.g. while scanning).
+    pub(crate) fn fraction(&self) -> Option<f32> {
+        (self.operations_planned > 0).then(|| self.operations_executed as f32 / self.operations_planned as f32)
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        match self.operations_planned {
+            0 => format!("{} directories scanned", self.directories_scanned),
+            planned => format!("{} of {planned} operations executed, {} bytes written", self.operations_executed, self.bytes_written),
+        }
+    }
+}
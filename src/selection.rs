@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use crate::apply::ApplyReport;
+use crate::cli::Mode;
+use crate::differences::{apply_diffs_source_to_target_with_prints, Difference, find_differences, verify_source_fully_newer_than_target};
+use crate::hooks::RunHooks;
+use crate::options::SyncOptions;
+use crate::outcome::Outcome;
+use crate::space::check_free_space;
+use crate::state::TargetLock;
+
+/// A profile of the config file that can be picked in the ui.
+pub(crate) struct UiProfile {
+    pub(crate) name: String,
+    pub(crate) source_path: String,
+    pub(crate) target_path: String,
+    /// Resolved for the target by each analysis and application, while they hold the lock.
+    pub(crate) options: SyncOptions,
+}
+
+/// The differences between source and target of which the user selects those to apply, behind the iced and the terminal ui.
+pub(crate) struct Selection {
+    pub(crate) source_path: String,
+    pub(crate) target_path: String,
+    pub(crate) options: SyncOptions,
+    pub(crate) selected_differences: Vec<(Difference, bool)>,
+    pub(crate) problems: HashMap<Difference, String>,
+    pub(crate) space_warning: Option<String>,
+    /// Set if the last analysis or apply did not proceed, because another run holds the lock on the target or a hook failed.
+    pub(crate) lock_message: Option<String>,
+    pub(crate) profiles: Vec<UiProfile>,
+    pub(crate) selected_profile: Option<String>,
+    /// Ui or Tui, passed to the hooks.
+    pub(crate) mode: Mode,
+}
+
+/// What an analysis found, kept apart from the selection so that the analysis can run in the background.
+#[derive(Debug, Clone)]
+pub(crate) struct Analysis {
+    pub(crate) differences: Vec<Difference>,
+    pub(crate) problems: HashMap<Difference, String>,
+    pub(crate) space_warning: Option<String>,
+}
+
+/// Which differences are shown.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Filter {
+    /// Shown are differences whose description or directory contains the text, ignoring case.
+    pub(crate) text: String,
+    pub(crate) problems_only: bool,
+}
+
+impl Selection {
+    pub(crate) fn new(source_path: String, target_path: String, options: SyncOptions, profiles: Vec<UiProfile>, mode: Mode) -> Selection {
+        Selection { source_path, target_path, options, selected_differences: Vec::new(), problems: HashMap::new(), space_warning: None, lock_message: None, profiles, selected_profile: None, mode }
+    }
+
+    /// Finds the differences again, those without problems are selected.
+    pub(crate) fn re_run_analysis(&mut self) {
+        let analysis = analyze(&self.source_path, &self.target_path, &self.options, self.mode);
+        self.show_analysis(analysis);
+    }
+
+    /// Replaces the differences with those of the analysis, those without problems are selected.
+    /// If the analysis did not proceed, the previous differences are kept.
+    pub(crate) fn show_analysis(&mut self, analysis: Result<Analysis, String>) {
+        let analysis = match analysis {
+            Ok(analysis) => analysis,
+            Err(e) => {
+                self.lock_message = Some(e);
+                return;
+            }
+        };
+        self.lock_message = None;
+        self.problems = analysis.problems;
+        self.space_warning = analysis.space_warning;
+        self.selected_differences = analysis.differences.into_iter()
+            .map(|d| {
+                let has_problem = self.problems.contains_key(&d);
+                (d, !has_problem)
+            })
+            .collect();
+    }
+
+    /// Applies the selected differences and analyzes again, None if the target is locked.
+    pub(crate) fn apply_selected_changes(&mut self) -> Option<ApplyReport> {
+        let report = apply(&self.source_path, &self.target_path, &self.selected(), &self.options, self.mode);
+        self.show_apply(report).inspect(|_| self.re_run_analysis())
+    }
+
+    /// Notes why applying did not proceed, returns the report if it did.
+    pub(crate) fn show_apply(&mut self, report: Result<ApplyReport, String>) -> Option<ApplyReport> {
+        match report {
+            Ok(report) => {
+                self.lock_message = None;
+                Some(report)
+            }
+            Err(e) => {
+                self.lock_message = Some(e);
+                None
+            }
+        }
+    }
+
+    /// The differences selected to be applied.
+    pub(crate) fn selected(&self) -> Vec<Difference> {
+        self.selected_differences.iter().filter(|(_, selected)| *selected).map(|(d, _)| d.clone()).collect()
+    }
+
+    /// Switches to the directories and options of the profile, the previous results are discarded.
+    pub(crate) fn select_profile(&mut self, name: String) {
+        let Some(profile) = self.profiles.iter().find(|p| p.name == name) else { return };
+        self.source_path = profile.source_path.clone();
+        self.target_path = profile.target_path.clone();
+        self.options = profile.options.clone();
+        self.selected_differences.clear();
+        self.problems.clear();
+        self.space_warning = None;
+        self.lock_message = None;
+        self.selected_profile = Some(name);
+    }
+
+    pub(crate) fn set_selected(&mut self, index: usize, selected: bool) {
+        self.selected_differences[index].1 = selected;
+    }
+
+    pub(crate) fn selected_count(&self) -> usize {
+        self.selected_differences.iter().filter(|(_, selected)| *selected).count()
+    }
+
+    pub(crate) fn directory_of(&self, d: &Difference) -> String {
+        d.get_directory_path(self.source_path.len(), self.target_path.len()).to_string()
+    }
+
+    /// Indices of the differences the filter shows, in the order they were found.
+    pub(crate) fn matching(&self, filter: &Filter) -> Vec<usize> {
+        let text = filter.text.to_lowercase();
+        self.selected_differences.iter().enumerate()
+            .filter(|(_, (d, _))| !filter.problems_only || self.problems.contains_key(d))
+            .filter(|(_, (d, _))| text.is_empty()
+                || d.describe_short().to_lowercase().contains(&text)
+                || self.directory_of(d).to_lowercase().contains(&text))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Why the last action did not proceed, or a warning about the last analysis.
+    pub(crate) fn status(&self) -> Option<String> {
+        match &self.lock_message {
+            Some(lock_message) => Some(lock_message.clone()),
+            None => self.space_warning.as_ref().map(|w| format!("Warning: {w}")),
+        }
+    }
+}
+
+/// Finds the differences while holding the lock on the target, fails if another run holds it or the pre-scan hook fails.
+pub(crate) fn analyze(source_path: &str, target_path: &str, options: &SyncOptions, mode: Mode) -> Result<Analysis, String> {
+    let _lock = TargetLock::acquire(target_path)?;
+    let options = &resolved_for_target(target_path, options);
+    let hooks = RunHooks::new(options, mode, source_path, target_path);
+    if let Err(e) = hooks.pre_scan() {
+        hooks.post_apply(&ApplyReport::default(), Outcome::Failed);
+        return Err(e);
+    }
+    let differences = find_differences(source_path, target_path, options);
+    let problems = verify_source_fully_newer_than_target(&differences, options);
+    hooks.post_scan(differences.len(), problems.len());
+    let space_warning = check_free_space(target_path, differences.iter(), options).err();
+    Ok(Analysis { differences, problems, space_warning })
+}
+
+/// Applies the differences while holding the lock on the target, fails if another run holds it or the pre-apply hook fails.
+pub(crate) fn apply(source_path: &str, target_path: &str, differences: &[Difference], options: &SyncOptions, mode: Mode) -> Result<ApplyReport, String> {
+    let _lock = TargetLock::acquire(target_path)?;
+    let options = &resolved_for_target(target_path, options);
+    let hooks = RunHooks::new(options, mode, source_path, target_path);
+    let problems = verify_source_fully_newer_than_target(&differences.to_vec(), options).len();
+    if let Err(e) = hooks.pre_apply(differences.len(), problems) {
+        hooks.post_apply(&ApplyReport::default(), Outcome::Failed);
+        return Err(e);
+    }
+    let report = apply_diffs_source_to_target_with_prints(source_path, target_path, differences.iter(), options);
+    hooks.post_apply(&report, Outcome::of_report(&report));
+    Ok(report)
+}
+
+/// The uis apply what they analyze, so they probe the target like other runs that write to it.
+fn resolved_for_target(target_path: &str, options: &SyncOptions) -> SyncOptions {
+    let mut options = options.clone();
+    options.resolve_for_target(target_path, true);
+    options
+}
@@ -0,0 +1,88 @@
+use std::io;
+use crate::differences::{AnnotatedPath, Difference};
+use crate::hardlinks::HardlinkChange;
+use crate::options::SyncOptions;
+
+/// Most bytes the differences occupy in the target at any point while they are applied, 0 if they free more than they add.
+///
+/// Up to `workers` differences are applied at the same time, in the order given, so the space a difference frees
+/// is only counted once the differences running next to it (which may finish later) have added theirs.
+/// Replaced files count with their new size while the old one is counted as freed, moved and linked files with nothing.
+/// If `keeps_removed` (replaced and deleted files are kept as versions in the target), nothing is freed.
+pub(crate) fn peak_bytes_required<'a>(differences: impl Iterator<Item=&'a Difference>, workers: usize, keeps_removed: bool) -> u64 {
+    let changes: Vec<(i64, i64)> = differences
+        .map(|d| {
+            let (added, freed) = added_and_freed_bytes(d);
+            (added, if keeps_removed { 0 } else { freed })
+        })
+        .collect();
+    let workers = workers.max(1);
+    let mut applied = 0i64;
+    let mut running: i64 = changes.iter().take(workers).map(|(added, _)| added).sum();
+    let mut peak = running;
+    for (i, (added, freed)) in changes.iter().enumerate() {
+        applied += added - freed;
+        running -= added;
+        if let Some((next_added, _)) = changes.get(i + workers) {
+            running += next_added;
+        }
+        peak = peak.max(applied + running);
+    }
+    peak.max(0) as u64
+}
+
+fn added_and_freed_bytes(d: &Difference) -> (i64, i64) {
+    if d.collides_with.is_some() || d.p_moved_from.is_some() {
+        return (0, 0);
+    }
+    match (&d.p_source, &d.p_target, &d.hardlink) {
+        (_, _, Some(HardlinkChange::Link { .. })) => (0, 0),
+        (Some(ps), Some(pt), _) => (tree_size(ps), tree_size(pt)),
+        (Some(ps), None, _) => (tree_size(ps), 0),
+        (None, Some(pt), _) => (0, tree_size(pt)),
+        (None, None, _) => (0, 0),
+    }
+}
+
+/// Size of the file or of all files below the directory.
+fn tree_size(p: &AnnotatedPath) -> i64 {
+    if !p.is_dir() {
+        return p.len() as i64;
+    }
+    walkdir::WalkDir::new(&p.path).into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len() as i64)
+        .sum()
+}
+
+/// Bytes available to unprivileged users on the filesystem the directory is on.
+#[cfg(unix)]
+pub(crate) fn available_bytes(dir: &str) -> io::Result<u64> {
+    use std::ffi::CString;
+    let path = CString::new(dir).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: path is a valid nul terminated string, stat is written by the call
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn available_bytes(_dir: &str) -> io::Result<u64> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Fails with a description of the shortfall, if applying the differences could leave less than the free space margin in the target.
+/// If the check is off or the free space cannot be determined, the check passes.
+pub(crate) fn check_free_space<'a>(target_dir: &str, differences: impl Iterator<Item=&'a Difference>, options: &SyncOptions) -> Result<(), String> {
+    let Some(margin) = options.free_space_margin else { return Ok(()) };
+    let Ok(available) = available_bytes(target_dir) else { return Ok(()) };
+    let required = peak_bytes_required(differences, options.workers, options.retention.is_some());
+    if required.saturating_add(margin) <= available {
+        return Ok(());
+    }
+    Err(format!("Not enough free space in backup directory: {required} bytes required (plus a safety margin of {margin} bytes), but only {available} bytes available."))
+}
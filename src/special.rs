@@ -0,0 +1,63 @@
+use std::fs::Metadata;
+use std::io;
+
+/// Kinds of directory entries that are neither directories nor regular files.
+/// Copying them like files would block (FIFOs) or read from a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum SpecialKind {
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+impl SpecialKind {
+    pub(crate) fn marker(&self) -> &'static str {
+        match self {
+            SpecialKind::Fifo => "FIFO",
+            SpecialKind::Socket => "SOCKET",
+            SpecialKind::CharDevice => "CHARDEV",
+            SpecialKind::BlockDevice => "BLOCKDEV",
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn special_kind(meta: &Metadata) -> Option<SpecialKind> {
+    use std::os::unix::fs::FileTypeExt;
+    let file_type = meta.file_type();
+    if file_type.is_fifo() {
+        Some(SpecialKind::Fifo)
+    } else if file_type.is_socket() {
+        Some(SpecialKind::Socket)
+    } else if file_type.is_char_device() {
+        Some(SpecialKind::CharDevice)
+    } else if file_type.is_block_device() {
+        Some(SpecialKind::BlockDevice)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn special_kind(_meta: &Metadata) -> Option<SpecialKind> {
+    None
+}
+
+/// Creates a node at `to` of the same type, permissions and (for devices) device number as `from`.
+/// Creating device nodes usually requires privileges.
+#[cfg(unix)]
+pub(crate) fn recreate_special_file(from: &str, to: &str) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::symlink_metadata(from)?;
+    let path = CString::new(to).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: path is a valid nul terminated string
+    let result = unsafe { libc::mknod(path.as_ptr(), meta.mode() as libc::mode_t, meta.rdev() as libc::dev_t) };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn recreate_special_file(_from: &str, _to: &str) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
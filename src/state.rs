@@ -0,0 +1,135 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path::PathBuf;
+use log::warn;
+
+/// Directory in the root of the target, in which the synchronizer keeps its own files (lock, logs, ...).
+/// It is never compared or synchronized.
+pub(crate) const STATE_DIR_NAME: &str = ".directory_synchronizer";
+
+/// File in the state directory with the properties probed on the target filesystem (see SyncOptions::resolve_for_target),
+/// one "name=value" per line, so that runs which do not write to the target can use them.
+const PROBED_FILE_NAME: &str = "filesystem";
+
+pub(crate) fn state_dir(target_dir: &str) -> PathBuf {
+    PathBuf::from(target_dir).join(STATE_DIR_NAME)
+}
+
+/// The value of a property, as recorded by the last run that probed it.
+pub(crate) fn recall_probed(target_dir: &str, name: &str) -> Option<String> {
+    let probed = fs::read_to_string(state_dir(target_dir).join(PROBED_FILE_NAME)).ok()?;
+    probed.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix('=')).map(str::to_string)
+}
+
+/// Records the value of a property, replacing the previous one. Only called while holding the lock on the target.
+pub(crate) fn record_probed(target_dir: &str, name: &str, value: &str) -> io::Result<()> {
+    let path = state_dir(target_dir).join(PROBED_FILE_NAME);
+    let probed = fs::read_to_string(&path).unwrap_or_default();
+    let mut lines: Vec<&str> = probed.lines().filter(|line| line.split_once('=').map(|(n, _)| n) != Some(name)).collect();
+    let line = format!("{name}={value}");
+    lines.push(&line);
+    fs::write(&path, lines.join("\n") + "\n")
+}
+
+/// Advisory lock on a target directory, so that concurrent runs (e.g. the ui and a scheduled just-do-it) do not interfere.
+/// The lock file is locked with flock and contains "pid@host" of the holder. Released when dropped.
+pub(crate) struct TargetLock {
+    file: File,
+}
+
+impl TargetLock {
+    /// Takes the lock or fails with a description of its holder.
+    /// Locks that are not held anymore (the holder crashed) are taken over.
+    pub(crate) fn acquire(target_dir: &str) -> Result<TargetLock, String> {
+        let dir = state_dir(target_dir);
+        let path = dir.join("lock");
+        let mut file = std::fs::create_dir_all(&dir)
+            .and_then(|_| OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path))
+            .map_err(|e| format!("cannot open lock file {}: {e}", path.display()))?;
+        let mut holder = String::new();
+        file.read_to_string(&mut holder).ok();
+        let holder = holder.trim().to_string();
+
+        match try_flock(&file) {
+            //a recorded holder has exited (its flock was released with it)
+            Ok(true) => {}
+            Ok(false) => return Err(format!("target is locked by {}", if holder.is_empty() { "another process" } else { &holder })),
+            //flock is not supported by every (network) filesystem, then only the recorded holder is checked
+            Err(_) if !holder.is_empty() => {
+                if !is_stale(&holder) {
+                    return Err(format!("target is locked by {holder}"));
+                }
+                warn!("Taking over stale lock of {holder}");
+            }
+            Err(_) => {}
+        }
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| write!(file, "{}@{}", std::process::id(), hostname()))
+            .and_then(|_| file.flush())
+            .map_err(|e| format!("cannot write lock file {}: {e}", path.display()))?;
+        Ok(TargetLock { file })
+    }
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        //the flock itself is released when the file is closed
+        self.file.set_len(0).ok();
+    }
+}
+
+/// Whether the holder "pid@host" is a process on this host that does not exist anymore.
+/// Holders on other hosts cannot be checked and are never considered stale.
+fn is_stale(holder: &str) -> bool {
+    match holder.split_once('@') {
+        Some((pid, host)) if host == hostname() => pid.parse().is_ok_and(|pid| !process_exists(pid)),
+        _ => false,
+    }
+}
+
+/// Ok(false) if another open file holds the lock.
+#[cfg(unix)]
+fn try_flock(file: &File) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: flock on a valid descriptor
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EWOULDBLOCK) { Ok(false) } else { Err(e) }
+}
+
+#[cfg(not(unix))]
+fn try_flock(_file: &File) -> io::Result<bool> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(unix)]
+fn process_exists(pid: i32) -> bool {
+    // SAFETY: signal 0 only checks whether the process exists
+    unsafe { libc::kill(pid, 0) == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+}
+
+#[cfg(not(unix))]
+fn process_exists(_pid: i32) -> bool {
+    true
+}
+
+#[cfg(unix)]
+pub(crate) fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its length
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
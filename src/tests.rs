@@ -3,6 +3,7 @@ use differences::find_differences;
 use rand::random;
 use crate::differences;
 use crate::differences::{apply_diffs_source_to_target_with_prints, verify_source_fully_newer_than_target};
+use crate::options::SyncOptions;
 
 #[test]
 fn test_new_file_in_source() {
@@ -115,6 +116,1111 @@ fn test_file_deleted_in_source_and_other_file_changed() {
     run_synchronization_as_test(&source_path, &target_path, true);
 }
 
+#[test]
+fn test_many_files_in_new_and_deleted_directories_with_parallel_workers() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+
+    for d in 0..5 {
+        fs::create_dir_all(format!("{source_path}/d4/d4d{d}/nested")).ok();
+        fs::create_dir_all(format!("{target_path}/d5/d5d{d}/nested")).ok();
+        for f in 0..20 {
+            fs::write(format!("{source_path}/d4/d4d{d}/nested/f{f}"), [d, f, 1]).ok();
+            fs::write(format!("{target_path}/d5/d5d{d}/nested/f{f}"), [d, f, 2]).ok();
+        }
+    }
+
+    let diffs = find_differences(&source_path, &target_path, &SyncOptions::default());
+    let options = SyncOptions { workers: 8, ..SyncOptions::default() };
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(0, report.failed());
+    assert_eq!(100 * 3, report.bytes_written());
+    assert!(find_differences(&source_path, &target_path, &SyncOptions::default()).is_empty());
+}
+
+#[test]
+fn test_throttled_copy_respects_byte_rate() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+
+    fs::write(format!("{source_path}/d2/d2f5"), vec![7u8; 200 * 1024]).ok();
+    fs::write(format!("{source_path}/d2/d2f6"), vec![7u8; 200 * 1024]).ok();
+
+    let options = SyncOptions { max_bytes_per_second: Some(1024 * 1024), max_files_per_second: Some(100.0), ..SyncOptions::default() };
+    let start = std::time::Instant::now();
+    let diffs = find_differences(&source_path, &target_path, &SyncOptions::default());
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(0, report.failed());
+    assert_eq!(400 * 1024, report.bytes_written());
+    assert!(start.elapsed() >= std::time::Duration::from_millis(350));
+    assert!(find_differences(&source_path, &target_path, &SyncOptions::default()).is_empty());
+}
+
+#[test]
+fn test_config_file_options_are_overridden_by_args() {
+    let config = crate::config::parse_config("workers = 3\nmax_bytes_per_second = \"1K\"\nmax_files_per_second = 2.5").unwrap();
+    let mut options = SyncOptions::default();
+    config.apply_to(&mut options).unwrap();
+    assert_eq!(Some(1024), options.max_bytes_per_second);
+    let options = options_from_args(&["--max-bytes-per-second=2M"], options);
+    assert_eq!(3, options.workers);
+    assert_eq!(Some(2 * 1024 * 1024), options.max_bytes_per_second);
+    assert_eq!(Some(2.5), options.max_files_per_second);
+
+    assert!(crate::config::parse_config("unknown_option = 1").is_err());
+    assert!(crate::config::parse_config("detect_moves = \"sometimes\"").unwrap().apply_to(&mut SyncOptions::default()).is_err());
+}
+
+#[test]
+fn test_moved_files_are_moved_in_target() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f2"), [1,2,3]).ok();
+    run_synchronization_as_test(&source_path, &target_path, true);
+
+    fs::rename(format!("{source_path}/d1/d1f1"), format!("{source_path}/d2/d2d1/d1f1")).ok();
+    fs::rename(format!("{source_path}/f2"), format!("{source_path}/d3/f2-renamed")).ok();
+    let moved_inode_before = std::os::unix::fs::MetadataExt::ino(&fs::metadata(format!("{target_path}/f2")).unwrap());
+
+    for move_detection in [crate::options::MoveDetection::SizeAndTime, crate::options::MoveDetection::Hash] {
+        let options = SyncOptions { move_detection, ..SyncOptions::default() };
+        let diffs = find_differences(&source_path, &target_path, &options);
+        assert_eq!(2, diffs.len());
+        assert!(diffs.iter().all(|d| d.p_moved_from.is_some()));
+        assert!(diffs.iter().any(|d| d.describe().ends_with("f2 \u{2192} d3/f2-renamed")));
+        assert!(verify_source_fully_newer_than_target(&diffs, &options).is_empty());
+    }
+
+    let options = SyncOptions { move_detection: crate::options::MoveDetection::Hash, ..SyncOptions::default() };
+    let diffs = find_differences(&source_path, &target_path, &options);
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(0, report.failed());
+    assert_eq!(0, report.bytes_written());
+    assert!(find_differences(&source_path, &target_path, &SyncOptions::default()).is_empty());
+    let moved_inode_after = std::os::unix::fs::MetadataExt::ino(&fs::metadata(format!("{target_path}/d3/f2-renamed")).unwrap());
+    assert_eq!(moved_inode_before, moved_inode_after);
+}
+
+#[test]
+fn test_moved_directories_are_moved_in_target() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::rename(format!("{source_path}/d3"), format!("{source_path}/d2/d3-moved")).ok();
+    //same entries, sizes and times, but other content
+    let modified = fs::metadata(format!("{source_path}/d1/d1f1")).unwrap().modified().unwrap();
+    fs::write(format!("{source_path}/d1/d1f1"), [9,9,9,9,9]).ok();
+    filetime::set_file_mtime(format!("{source_path}/d1/d1f1"), filetime::FileTime::from(modified)).ok();
+    fs::rename(format!("{source_path}/d1"), format!("{source_path}/d1-renamed")).ok();
+    let moved_inode_before = std::os::unix::fs::MetadataExt::ino(&fs::metadata(format!("{target_path}/d3")).unwrap());
+
+    let options = SyncOptions { move_detection: crate::options::MoveDetection::SizeAndTime, ..SyncOptions::default() };
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(2, diffs.len());
+    assert!(diffs.iter().all(|d| d.p_moved_from.is_some()));
+
+    let options = SyncOptions { move_detection: crate::options::MoveDetection::Hash, ..SyncOptions::default() };
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(3, diffs.len());
+    assert!(diffs.iter().any(|d| d.describe().ends_with("d3 \u{2192} d2/d3-moved")));
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(0, report.failed());
+    assert!(find_differences(&source_path, &target_path, &SyncOptions::default()).is_empty());
+    let moved_inode_after = std::os::unix::fs::MetadataExt::ino(&fs::metadata(format!("{target_path}/d2/d3-moved")).unwrap());
+    assert_eq!(moved_inode_before, moved_inode_after);
+}
+
+#[test]
+fn test_moved_file_with_changed_content_is_not_paired_by_hash() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+
+    let modified = fs::metadata(format!("{source_path}/f2")).unwrap().modified().unwrap();
+    fs::remove_file(format!("{source_path}/f2")).ok();
+    fs::write(format!("{source_path}/d1/f2"), [9,9,9,9,9]).ok();
+    filetime::set_file_mtime(format!("{source_path}/d1/f2"), filetime::FileTime::from(modified)).ok();
+
+    let options = SyncOptions { move_detection: crate::options::MoveDetection::Hash, ..SyncOptions::default() };
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(2, diffs.len());
+    assert!(diffs.iter().all(|d| d.p_moved_from.is_none()));
+}
+
+#[test]
+fn test_large_modified_file_is_updated_block_wise() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let block_size = crate::delta::DELTA_BLOCK_SIZE;
+
+    let mut content: Vec<u8> = (0..10 * block_size).map(|i| (i % 251) as u8).collect();
+    fs::write(format!("{source_path}/d1/large"), &content).ok();
+    run_synchronization_as_test(&source_path, &target_path, true);
+
+    content[3 * block_size + 17] ^= 0xff;
+    content.truncate(9 * block_size + 5);
+    fs::write(format!("{source_path}/d1/large"), &content).ok();
+
+    let options = SyncOptions { delta_min_size: Some(block_size as u64), ..SyncOptions::default() };
+    let diffs = find_differences(&source_path, &target_path, &options);
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(0, report.failed());
+    assert_eq!(block_size as u64, report.bytes_written());
+    assert_eq!(content, fs::read(format!("{target_path}/d1/large")).unwrap());
+    assert!(find_differences(&source_path, &target_path, &options).is_empty());
+}
+
+#[test]
+fn test_sparse_file_stays_sparse_and_copy_method_is_reported() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+
+    let file = fs::File::create(format!("{source_path}/sparse")).unwrap();
+    file.set_len(64 * 1024 * 1024).unwrap();
+    std::os::unix::fs::FileExt::write_at(&file, &[1, 2, 3], 32 * 1024 * 1024).unwrap();
+    drop(file);
+
+    let diffs = find_differences(&source_path, &target_path, &SyncOptions::default());
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &SyncOptions::default());
+    assert_eq!(0, report.failed());
+    assert!(report.results.iter().all(|r| r.method.is_some()));
+    assert_eq!(fs::read(format!("{source_path}/sparse")).unwrap(), fs::read(format!("{target_path}/sparse")).unwrap());
+    let allocated = std::os::unix::fs::MetadataExt::blocks(&fs::metadata(format!("{target_path}/sparse")).unwrap()) * 512;
+    assert!(allocated < 16 * 1024 * 1024);
+    assert!(find_differences(&source_path, &target_path, &SyncOptions::default()).is_empty());
+}
+
+#[test]
+fn test_hardlinks_are_preserved_in_target() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let inode = |path: String| std::os::unix::fs::MetadataExt::ino(&fs::metadata(path).unwrap());
+
+    fs::write(format!("{source_path}/f3"), [3,3,3]).ok();
+    fs::hard_link(format!("{source_path}/f3"), format!("{source_path}/d1/f3-link")).ok();
+    fs::create_dir(format!("{source_path}/d4")).ok();
+    fs::hard_link(format!("{source_path}/f3"), format!("{source_path}/d4/f3-link")).ok();
+    run_synchronization_as_test(&source_path, &target_path, true);
+    assert_eq!(inode(format!("{target_path}/f3")), inode(format!("{target_path}/d1/f3-link")));
+    assert_eq!(inode(format!("{target_path}/f3")), inode(format!("{target_path}/d4/f3-link")));
+
+    //link broken in backup
+    fs::remove_file(format!("{target_path}/d1/f3-link")).ok();
+    fs::copy(format!("{target_path}/f3"), format!("{target_path}/d1/f3-link")).ok();
+    let modified = fs::metadata(format!("{source_path}/f3")).unwrap().modified().unwrap();
+    filetime::set_file_mtime(format!("{target_path}/d1/f3-link"), filetime::FileTime::from(modified)).ok();
+    let diffs = find_differences(&source_path, &target_path, &SyncOptions::default());
+    assert_eq!(1, diffs.len());
+    assert!(diffs[0].describe().starts_with("HARDLINKED in source"));
+    run_synchronization_as_test(&source_path, &target_path, true);
+    assert_eq!(inode(format!("{target_path}/f3")), inode(format!("{target_path}/d1/f3-link")));
+
+    //link removed in source
+    fs::remove_file(format!("{source_path}/d4/f3-link")).ok();
+    fs::copy(format!("{source_path}/f3"), format!("{source_path}/d4/f3-link")).ok();
+    filetime::set_file_mtime(format!("{source_path}/d4/f3-link"), filetime::FileTime::from(modified)).ok();
+    let diffs = find_differences(&source_path, &target_path, &SyncOptions::default());
+    assert_eq!(1, diffs.len());
+    assert!(diffs[0].describe().starts_with("NO LONGER HARDLINKED in source"));
+    run_synchronization_as_test(&source_path, &target_path, true);
+    assert_ne!(inode(format!("{target_path}/f3")), inode(format!("{target_path}/d4/f3-link")));
+    assert_eq!(inode(format!("{target_path}/f3")), inode(format!("{target_path}/d1/f3-link")));
+}
+
+#[test]
+fn test_special_files_are_skipped_recreated_or_refused() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let is_fifo = |path: String| fs::symlink_metadata(path).is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_fifo(&m.file_type()));
+
+    fs::create_dir(format!("{source_path}/d4")).ok();
+    for fifo in [format!("{source_path}/fifo"), format!("{source_path}/d4/fifo")] {
+        let path = std::ffi::CString::new(fifo).unwrap();
+        assert_eq!(0, unsafe { libc::mkfifo(path.as_ptr(), 0o644) });
+    }
+
+    let diffs = find_differences(&source_path, &target_path, &SyncOptions::default());
+    assert!(diffs.iter().any(|d| d.describe() == "NEW in source (or deleted in backup): FIFO[fifo]"));
+
+    let options = SyncOptions { special_files: crate::options::SpecialFilePolicy::Error, ..SyncOptions::default() };
+    assert_eq!(1, verify_source_fully_newer_than_target(&diffs, &options).len());
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(2, report.failed());
+
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &SyncOptions::default());
+    assert_eq!(0, report.failed());
+    assert!(!is_fifo(format!("{target_path}/fifo")));
+    assert!(fs::metadata(format!("{target_path}/d4")).is_ok_and(|m| m.is_dir()));
+
+    let options = SyncOptions { special_files: crate::options::SpecialFilePolicy::Recreate, ..SyncOptions::default() };
+    let diffs = find_differences(&source_path, &target_path, &options);
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(0, report.failed());
+    assert!(is_fifo(format!("{target_path}/fifo")));
+    assert!(is_fifo(format!("{target_path}/d4/fifo")));
+    assert!(find_differences(&source_path, &target_path, &options).is_empty());
+}
+
+#[test]
+fn test_one_file_system_skips_mount_points() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let mount_point = format!("{source_path}/d2/mounted");
+    fs::create_dir(&mount_point).ok();
+    let c_mount_point = std::ffi::CString::new(mount_point.clone()).unwrap();
+    let tmpfs = std::ffi::CString::new("tmpfs").unwrap();
+    if unsafe { libc::mount(tmpfs.as_ptr(), c_mount_point.as_ptr(), tmpfs.as_ptr(), 0, std::ptr::null()) } != 0 {
+        println!("cannot mount a tmpfs (requires privileges), skipping test");
+        return;
+    }
+    fs::write(format!("{mount_point}/on-other-fs"), [1,2,3]).ok();
+
+    let options = SyncOptions { one_file_system: true, ..SyncOptions::default() };
+    let diffs = find_differences(&source_path, &target_path, &options);
+    let with_mount_point = find_differences(&source_path, &target_path, &SyncOptions::default());
+
+    //new directories are copied and deleted directories removed without what is mounted below them
+    fs::create_dir_all(format!("{source_path}/new/mounted")).ok();
+    fs::create_dir_all(format!("{target_path}/deleted/mounted")).ok();
+    fs::write(format!("{target_path}/deleted/f"), [1,2,3]).ok();
+    let c_new = std::ffi::CString::new(format!("{source_path}/new/mounted")).unwrap();
+    let c_deleted = std::ffi::CString::new(format!("{target_path}/deleted/mounted")).unwrap();
+    unsafe {
+        libc::mount(tmpfs.as_ptr(), c_new.as_ptr(), tmpfs.as_ptr(), 0, std::ptr::null());
+        libc::mount(tmpfs.as_ptr(), c_deleted.as_ptr(), tmpfs.as_ptr(), 0, std::ptr::null());
+    }
+    fs::write(format!("{source_path}/new/mounted/on-other-fs"), [1,2,3]).ok();
+    fs::write(format!("{target_path}/deleted/mounted/on-other-fs"), [1,2,3]).ok();
+    let new_and_deleted = find_differences(&source_path, &target_path, &options);
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, new_and_deleted.iter(), &options);
+    let mounted_kept = fs::exists(format!("{target_path}/deleted/mounted/on-other-fs")).unwrap();
+    unsafe {
+        libc::umount(c_new.as_ptr());
+        libc::umount(c_deleted.as_ptr());
+        libc::umount(c_mount_point.as_ptr());
+    }
+
+    assert!(diffs.is_empty());
+    assert_eq!(1, with_mount_point.len());
+    assert_eq!((2, 0), (new_and_deleted.len(), report.failed()));
+    assert!(fs::exists(format!("{target_path}/new")).unwrap());
+    assert!(!fs::exists(format!("{target_path}/new/mounted")).unwrap());
+    assert!(mounted_kept);
+    assert!(!fs::exists(format!("{target_path}/deleted/f")).unwrap());
+    assert!(options_from_args(&["--one-file-system"], SyncOptions::default()).one_file_system);
+}
+
+#[test]
+fn test_mtime_tolerance_for_coarse_target_timestamps() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    //like a FAT drive, which stores modification times with a granularity of 2 seconds
+    let modified = fs::metadata(format!("{source_path}/f1")).unwrap().modified().unwrap();
+    let truncated = modified - std::time::Duration::from_millis(1500);
+    filetime::set_file_mtime(format!("{target_path}/f1"), filetime::FileTime::from(truncated)).ok();
+    let later = modified + std::time::Duration::from_millis(1500);
+    filetime::set_file_mtime(format!("{target_path}/f2"), filetime::FileTime::from(later)).ok();
+
+    let diffs = find_differences(&source_path, &target_path, &SyncOptions::default());
+    assert_eq!(2, diffs.len());
+    assert_eq!(1, verify_source_fully_newer_than_target(&diffs, &SyncOptions::default()).len());
+
+    let options = SyncOptions { mtime_tolerance: crate::options::MtimeTolerance::parse("", "2s").unwrap(), ..SyncOptions::default() };
+    assert!(crate::options::MtimeTolerance::parse("", "99999999999999999s").is_err());
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert!(diffs.is_empty());
+    assert!(verify_source_fully_newer_than_target(&diffs, &options).is_empty());
+
+    //runs that do not write to the target do not probe it, until a run that writes recorded the granularity
+    let auto = SyncOptions { mtime_tolerance: crate::options::MtimeTolerance::Auto, ..SyncOptions::default() };
+    let mut read_only = auto.clone();
+    read_only.resolve_mtime_tolerance(&target_path, false);
+    assert_eq!(crate::options::MtimeTolerance::Fixed(std::time::Duration::ZERO), read_only.mtime_tolerance);
+    assert!(!fs::exists(crate::state::state_dir(&target_path)).unwrap());
+
+    let _lock = crate::state::TargetLock::acquire(&target_path).unwrap();
+    let mut options = auto.clone();
+    options.resolve_mtime_tolerance(&target_path, true);
+    assert!(options.mtime_tolerance.duration() < std::time::Duration::from_secs(1));
+    assert!(fs::read_dir(&target_path).unwrap().all(|e| !e.unwrap().file_name().to_str().unwrap().contains("probe")));
+    assert!(fs::read_dir(crate::state::state_dir(&target_path)).unwrap().all(|e| !e.unwrap().file_name().to_str().unwrap().contains("probe")));
+    let mut read_only = auto.clone();
+    read_only.resolve_mtime_tolerance(&target_path, false);
+    assert_eq!(options.mtime_tolerance, read_only.mtime_tolerance);
+}
+
+#[test]
+fn test_case_insensitive_target_renames_entries_differing_in_case() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let options = SyncOptions { case_sensitivity: crate::options::CaseSensitivity::Insensitive, ..SyncOptions::default() };
+
+    fs::rename(format!("{source_path}/f1"), format!("{source_path}/F1")).ok();
+    fs::rename(format!("{source_path}/d1"), format!("{source_path}/D1")).ok();
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(2, diffs.len());
+    assert!(diffs.iter().all(|d| d.p_moved_from.is_some()));
+    assert!(verify_source_fully_newer_than_target(&diffs, &options).is_empty());
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(0, report.failed());
+    assert!(fs::metadata(format!("{target_path}/F1")).is_ok());
+    assert!(fs::metadata(format!("{target_path}/D1/d1f2")).is_ok());
+}
+
+#[test]
+fn test_case_insensitive_target_skips_colliding_source_names() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let options = SyncOptions { case_sensitivity: crate::options::CaseSensitivity::Insensitive, ..SyncOptions::default() };
+
+    fs::write(format!("{source_path}/d2/README"), [1]).ok();
+    fs::write(format!("{source_path}/d2/readme"), [2]).ok();
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(2, diffs.len());
+    let problems = verify_source_fully_newer_than_target(&diffs, &options);
+    assert_eq!(1, problems.len());
+    assert_eq!("COLLISION: FILE[\"readme\"] \u{2194} d2/README", problems.keys().next().unwrap().describe_short());
+
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(1, report.results.len());
+    assert!(fs::metadata(format!("{target_path}/d2/README")).is_ok());
+    assert!(fs::metadata(format!("{target_path}/d2/readme")).is_err());
+}
+
+#[test]
+fn test_unicode_normalized_names_are_renamed() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let options = SyncOptions { normalize_unicode: true, ..SyncOptions::default() };
+    let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+
+    fs::write(format!("{source_path}/{decomposed}"), [1,2,3]).ok();
+    run_synchronization_as_test(&source_path, &target_path, true);
+    fs::rename(format!("{source_path}/{decomposed}"), format!("{source_path}/{composed}")).ok();
+    assert_eq!(2, find_differences(&source_path, &target_path, &SyncOptions::default()).len());
+
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(1, diffs.len());
+    assert!(diffs[0].p_moved_from.is_some());
+    apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert!(fs::metadata(format!("{target_path}/{composed}")).is_ok());
+    assert!(fs::metadata(format!("{target_path}/{decomposed}")).is_err());
+}
+
+#[test]
+fn test_unicode_normalized_names_collide_in_the_source() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let options = SyncOptions { normalize_unicode: true, ..SyncOptions::default() };
+    let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+
+    fs::write(format!("{source_path}/{composed}"), [1,2,3]).ok();
+    run_synchronization_as_test(&source_path, &target_path, true);
+    fs::write(format!("{source_path}/{decomposed}"), [4]).ok();
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(1, verify_source_fully_newer_than_target(&diffs, &options).len());
+}
+
+#[test]
+fn test_unicode_normalized_directory_rename_updates_its_children() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let options = SyncOptions { normalize_unicode: true, ..SyncOptions::default() };
+    let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+    fs::create_dir(format!("{source_path}/{decomposed}")).ok();
+    fs::write(format!("{source_path}/{decomposed}/modified"), [1]).ok();
+    fs::write(format!("{source_path}/{decomposed}/deleted"), [2]).ok();
+    run_synchronization_as_test(&source_path, &target_path, true);
+
+    fs::rename(format!("{source_path}/{decomposed}"), format!("{source_path}/{composed}")).ok();
+    fs::write(format!("{source_path}/{composed}/modified"), [3, 4]).ok();
+    fs::remove_file(format!("{source_path}/{composed}/deleted")).ok();
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(3, diffs.len());
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!((3, 0), (report.results.len(), report.failed()));
+    assert_eq!(vec![3u8, 4], fs::read(format!("{target_path}/{composed}/modified")).unwrap());
+    assert!(fs::metadata(format!("{target_path}/{composed}/deleted")).is_err());
+    assert!(fs::metadata(format!("{target_path}/{decomposed}")).is_err());
+    assert!(find_differences(&source_path, &target_path, &options).is_empty());
+}
+
+#[test]
+fn test_free_space_check_refuses_to_start() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/large"), vec![7u8; 100_000]).ok();
+    fs::remove_file(format!("{source_path}/f1")).ok();
+
+    let diffs = find_differences(&source_path, &target_path, &SyncOptions::default());
+    assert_eq!(None, SyncOptions::default().free_space_margin);
+    //f1 may only be removed after the large file was copied next to it
+    assert_eq!(100_000, crate::space::peak_bytes_required(diffs.iter(), 2, false));
+    assert_eq!(100_000 - 5, crate::space::peak_bytes_required(diffs.iter(), 1, false));
+    assert_eq!(100_000, crate::space::peak_bytes_required(diffs.iter(), 1, true));
+    let options = SyncOptions { free_space_margin: Some(0), ..SyncOptions::default() };
+    assert!(crate::space::check_free_space(&target_path, diffs.iter(), &options).is_ok());
+    assert!(crate::space::check_free_space(&target_path, diffs.iter(), &SyncOptions::default()).is_ok());
+
+    let options = SyncOptions { free_space_margin: Some(u64::MAX / 2), ..SyncOptions::default() };
+    assert!(crate::space::check_free_space(&target_path, diffs.iter(), &options).is_err());
+    assert!(crate::differences::apply_during_analysis_with_prints(&source_path, &target_path, &options, &Default::default()).is_err());
+    assert!(fs::metadata(format!("{target_path}/large")).is_err());
+    assert!(crate::differences::apply_during_analysis_with_prints(&source_path, &target_path, &SyncOptions::default(), &Default::default()).is_ok_and(|r| r.failed() == 0));
+    assert!(fs::metadata(format!("{target_path}/large")).is_ok());
+}
+
+#[test]
+fn test_target_lock_is_exclusive_and_ignored_by_analysis() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let lock = crate::state::TargetLock::acquire(&target_path).unwrap();
+    let holder = format!("{}@{}", std::process::id(), crate::state::hostname());
+    assert_eq!(Err(format!("target is locked by {holder}")), crate::state::TargetLock::acquire(&target_path).map(|_| ()));
+    assert!(find_differences(&source_path, &target_path, &SyncOptions::default()).is_empty());
+    drop(lock);
+
+    //left behind by a process that crashed
+    fs::write(crate::state::state_dir(&target_path).join("lock"), "999999999@elsewhere").ok();
+    assert!(crate::state::TargetLock::acquire(&target_path).is_ok());
+}
+
+#[test]
+fn test_json_records_of_differences_operations_and_summary() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let options = options_from_args(&["--format", "json"], SyncOptions::default());
+    assert_eq!(crate::options::OutputFormat::Json, options.output_format);
+
+    fs::write(format!("{source_path}/d1/d1f3"), [1,2,3]).ok();
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(1, diffs.len());
+    let record = serde_json::to_value(crate::output::difference_record(&diffs[0], None)).unwrap();
+    assert_eq!("difference", record["type"]);
+    assert_eq!("new", record["kind"]);
+    assert_eq!("file", record["entry"]);
+    assert_eq!("d1/d1f3", record["path"]);
+    assert!(record["target"].is_null());
+
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    let record = serde_json::to_value(crate::output::operation_record(&report.results[0])).unwrap();
+    assert_eq!("operation", record["type"]);
+    assert_eq!("copy_file", record["operation"]);
+    assert_eq!(3, record["bytes"]);
+    let record = serde_json::to_value(crate::output::summary_record("t", &report, Some(1), Some(0), crate::outcome::Outcome::Success)).unwrap();
+    assert_eq!(serde_json::json!({"type": "summary", "target": "t", "differences": 1, "problems": 0, "operations": 1, "failed": 0, "bytes_written": 3,
+        "by_operation": {"copy_file": {"count": 1, "failed": 0, "bytes": 3}}, "exit_code": 0}), record);
+}
+
+#[test]
+fn test_outcome_and_summary_by_operation() {
+    use crate::apply::{ApplyReport, Operation, OperationResult};
+    use crate::outcome::Outcome;
+    let result = |operation: Operation, bytes: u64, error: Option<&str>| OperationResult { operation, bytes, method: None, error: error.map(str::to_string) };
+    let mut report = ApplyReport::default();
+    assert_eq!(Outcome::NoChanges, Outcome::of_report(&report));
+
+    report.results.push(result(Operation::RemoveFile { path: "t/f1".to_string() }, 0, None));
+    report.results.push(result(Operation::CopyFile { from: "s/f2".to_string(), to: "t/f2".to_string(), modified: std::time::SystemTime::now(), replace: false, link_group: None }, 5, None));
+    report.results.push(result(Operation::CopyFile { from: "s/f3".to_string(), to: "t/f3".to_string(), modified: std::time::SystemTime::now(), replace: true, link_group: None }, 7, None));
+    assert_eq!(Outcome::Success, Outcome::of_report(&report));
+    assert_eq!("3 operations executed, 0 failed, 12 bytes written.\n    \
+                copy_file: 2 executed, 0 failed, 12 bytes written\n    \
+                remove_file: 1 executed, 0 failed, 0 bytes written", report.summary());
+
+    report.results.push(result(Operation::RemoveDir { path: "t/d1".to_string() }, 0, Some("Directory not empty")));
+    assert_eq!(Outcome::PartialFailure, Outcome::of_report(&report));
+    assert_eq!(1, report.by_operation()["remove_dir"].failed);
+    assert_ne!(Outcome::PartialFailure.exit_code(), Outcome::NoChanges.exit_code());
+}
+
+#[test]
+fn test_outcome_of_a_selection_leaving_problems_unapplied() {
+    use crate::apply::{ApplyReport, Operation, OperationResult};
+    use crate::outcome::Outcome;
+    let mut report = ApplyReport::default();
+    report.results.push(OperationResult { operation: Operation::RemoveFile { path: "t/f1".to_string() }, bytes: 0, method: None, error: None });
+    assert_eq!(Outcome::Success, Outcome::of_selection(&report, 0));
+    assert_eq!(Outcome::ProblemsNotApplied, Outcome::of_selection(&report, 1));
+    assert_eq!(Outcome::ProblemsNotApplied, Outcome::of_selection(&ApplyReport::default(), 1));
+
+    report.results.push(OperationResult { operation: Operation::RemoveDir { path: "t/d1".to_string() }, bytes: 0, method: None, error: Some("Directory not empty".to_string()) });
+    assert_eq!(Outcome::PartialFailure, Outcome::of_selection(&report, 1));
+}
+
+#[test]
+fn test_command_line_subcommands_and_legacy_modes() {
+    use crate::cli::{Command, try_parse};
+    let cli = try_parse(["ds", "sync", "s", "t", "-i", "--workers=3", "--free-space-margin", "off"]).unwrap();
+    assert!(matches!(cli.command, Command::Sync { interactive: true, .. }));
+    let options = options_from_args(&["--workers=3", "--free-space-margin", "off", "--detect-moves=hash"], SyncOptions::default());
+    assert_eq!((3, None, crate::options::MoveDetection::Hash), (options.workers, options.free_space_margin, options.move_detection));
+
+    assert!(matches!(try_parse(["ds", "s", "t", "cmd", "--workers", "2"]).unwrap().command, Command::Sync { interactive: true, .. }));
+    assert!(matches!(try_parse(["ds", "s", "t", "just-do-it"]).unwrap().command, Command::Sync { interactive: false, .. }));
+    assert!(matches!(try_parse(["ds", "just-do-it", "s", "t"]).unwrap().command, Command::Sync { interactive: false, .. }));
+    assert!(matches!(try_parse(["ds", "s", "t", "ui"]).unwrap().command, Command::Ui(_)));
+    assert!(matches!(try_parse(["ds", "completions", "bash"]).unwrap().command, Command::Completions { .. }));
+
+    assert!(try_parse(["ds", "s", "t", "nonsense"]).is_err());
+    assert!(try_parse(["ds", "sync", "s", "t", "--detect-moves=sometimes"]).is_err());
+    assert!(try_parse(["ds", "sync", "s", "t", "--free-space-margin=99999999999999G"]).is_err());
+    assert_eq!(clap::error::ErrorKind::DisplayVersion, try_parse(["ds", "--version"]).unwrap_err().kind());
+}
+
+#[test]
+fn test_verbosity_flags_and_log_file_lines() {
+    use log::LevelFilter;
+    let args = |args: &[&str]| options_from_args(args, SyncOptions::default());
+    assert_eq!(LevelFilter::Info, crate::logging::console_level(&args(&[])));
+    assert_eq!(LevelFilter::Trace, crate::logging::console_level(&args(&["-vv", "--verbose"])));
+    assert_eq!(LevelFilter::Debug, crate::logging::console_level(&args(&["-vv", "-q"])));
+    assert_eq!(LevelFilter::Off, crate::logging::console_level(&args(&["-qqq"])));
+    assert_eq!(LevelFilter::Trace, crate::logging::console_level(&args(&[&format!("-{}", "v".repeat(200))])));
+    assert_eq!(LevelFilter::Warn, crate::logging::console_level(&args(&["--format=json"])));
+    //the log file always records the operations
+    assert_eq!(LevelFilter::Info, crate::logging::file_level(&args(&["-q", "--log-file"])));
+    assert!(args(&["--log-file"]).log_file);
+
+    let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(86400);
+    assert_eq!("1970-01-02T00:00:00Z ERROR failed\n    details\n", crate::logging::file_line(time, log::Level::Error, "failed\n    details"));
+}
+
+#[test]
+fn test_exclude_patterns_match_names_directories_and_paths() {
+    let mut excludes = crate::filters::Excludes::default();
+    excludes.add_all(&["*.tmp", "cache/", "d1/build", "docs/**/*.pdf"]);
+    assert!(excludes.is_excluded("a.tmp", false));
+    assert!(excludes.is_excluded("d1/d2/a.tmp", false));
+    assert!(!excludes.is_excluded("a.tmpx", false));
+    assert!(excludes.is_excluded("d1/cache", true));
+    assert!(!excludes.is_excluded("d1/cache", false));
+    assert!(excludes.is_excluded("d1/build", false));
+    assert!(!excludes.is_excluded("d2/d1/build", false));
+    assert!(excludes.is_excluded("docs/manual.pdf", false));
+    assert!(excludes.is_excluded("docs/a/b/manual.pdf", false));
+}
+
+#[test]
+fn test_excluded_paths_are_neither_compared_nor_copied() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    fs::write(format!("{source_path}/d1/new.tmp"), [1]).ok();
+    fs::write(format!("{target_path}/old.tmp"), [1]).ok();
+    fs::create_dir_all(format!("{source_path}/d4/cache")).ok();
+    fs::write(format!("{source_path}/d4/cache/c"), [1]).ok();
+    fs::write(format!("{source_path}/d4/f"), [1]).ok();
+
+    let options = options_from_args(&["--exclude=*.tmp", "--exclude", "cache/"], SyncOptions::default());
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(2, diffs.len());
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(0, report.failed());
+    assert!(find_differences(&source_path, &target_path, &options).is_empty());
+    assert!(fs::metadata(format!("{target_path}/d4/f")).is_ok());
+    assert!(fs::metadata(format!("{target_path}/d4/cache")).is_err());
+    assert!(fs::metadata(format!("{target_path}/d1/new.tmp")).is_err());
+    assert!(fs::metadata(format!("{target_path}/old.tmp")).is_ok());
+}
+
+#[test]
+fn test_checksum_comparison_ignores_modification_times() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let modified = fs::metadata(format!("{source_path}/f1")).unwrap().modified().unwrap();
+    //same content, different modification time
+    filetime::set_file_mtime(format!("{target_path}/f1"), filetime::FileTime::from(modified - std::time::Duration::from_secs(60))).ok();
+    //different content of the same size, same modification time
+    fs::write(format!("{target_path}/f2"), [5,4,3,2,1]).ok();
+    filetime::set_file_mtime(format!("{target_path}/f2"), filetime::FileTime::from(modified)).ok();
+    filetime::set_file_mtime(format!("{source_path}/f2"), filetime::FileTime::from(modified)).ok();
+
+    let options = options_from_args(&["--comparison=checksum"], SyncOptions::default());
+    let diffs = find_differences(&source_path, &target_path, &options);
+    assert_eq!(1, diffs.len());
+    let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert_eq!(0, report.failed());
+    assert_eq!(vec![1,2,3,4,5], fs::read(format!("{target_path}/f2")).unwrap());
+    assert!(find_differences(&source_path, &target_path, &options).is_empty());
+}
+
+#[test]
+fn test_retention_keeps_replaced_and_deleted_files() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let options = options_from_args(&["--retention=2"], SyncOptions::default());
+    for run in 0..3u8 {
+        //of different sizes, as writes in quick succession may not change the modification time
+        fs::write(format!("{source_path}/d1/d1f1"), vec![run; run as usize + 1]).ok();
+        let diffs = find_differences(&source_path, &target_path, &options);
+        let report = apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+        assert_eq!(0, report.failed());
+    }
+    fs::remove_file(format!("{source_path}/f2")).ok();
+    let diffs = find_differences(&source_path, &target_path, &options);
+    apply_diffs_source_to_target_with_prints(&source_path, &target_path, diffs.iter(), &options);
+    assert!(fs::metadata(format!("{target_path}/f2")).is_err());
+    assert!(find_differences(&source_path, &target_path, &options).is_empty());
+
+    let mut runs: Vec<_> = fs::read_dir(crate::versions::versions_dir(&target_path)).unwrap().map(|e| e.unwrap().path()).collect();
+    runs.sort();
+    assert_eq!(2, runs.len());
+    assert_eq!(vec![1u8; 2], fs::read(runs[0].join("d1/d1f1")).unwrap());
+    assert_eq!(vec![1,2,3,4,5], fs::read(runs[1].join("f2")).unwrap());
+}
+
+#[test]
+fn test_several_targets_from_one_source_listing() {
+    use crate::outcome::Outcome;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let second_target_path = format!("test-env-dirs/target_{}/", random::<u64>());
+    fs::create_dir(&second_target_path).ok();
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    let cli = crate::cli::try_parse(["ds", "sync", &source_path, &target_path, &second_target_path]).unwrap();
+    assert_eq!(2, cli.command.run_args().unwrap().1.targets.len());
+
+    let listing = crate::differences::SourceListing::default();
+    let options = SyncOptions::default();
+    assert_eq!(1, crate::differences::find_differences_with(&listing, &source_path, &target_path, &options).len());
+    //the source is not listed again, so changes after the first scan are only seen by later runs
+    fs::write(format!("{source_path}/f4"), [1]).ok();
+    assert_eq!(5, crate::differences::find_differences_with(&listing, &source_path, &second_target_path, &options).len());
+
+    //a locked target fails on its own, the other one is still synchronized
+    let _lock = crate::state::TargetLock::acquire(&target_path).unwrap();
+    let listing = crate::differences::SourceListing::default();
+    assert_eq!(Outcome::Failed, crate::synchronize_target(crate::cli::Mode::Sync, &source_path, &target_path, &options, &listing));
+    assert_eq!(Outcome::Success, crate::synchronize_target(crate::cli::Mode::Sync, &source_path, &second_target_path, &options, &listing));
+    assert!(find_differences(&source_path, &second_target_path, &options).is_empty());
+
+    assert_eq!(Outcome::NoChanges, Outcome::combine(&[Outcome::NoChanges, Outcome::NoChanges]));
+    assert_eq!(Outcome::Success, Outcome::combine(&[Outcome::Success, Outcome::NoChanges]));
+    assert_eq!(Outcome::PartialFailure, Outcome::combine(&[Outcome::Failed, Outcome::Success]));
+    assert_eq!(Outcome::Failed, Outcome::combine(&[Outcome::Failed, Outcome::Failed]));
+    assert_eq!(Outcome::ProblemsNotApplied, Outcome::combine(&[Outcome::ProblemsNotApplied, Outcome::Success]));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_watch_mode_applies_changed_paths() {
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+    use crate::watch::{affected_paths, apply_changes, SourceWatcher};
+    let options = options_from_args(&["--debounce=500ms", "--reconcile-interval", "off"], SyncOptions::default());
+    assert_eq!((Duration::from_millis(500), None), (options.debounce, options.reconcile_interval));
+
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let options = SyncOptions::default();
+    let mut watcher = SourceWatcher::new(&source_path, &options).unwrap();
+    let wait = Some(Duration::from_secs(5));
+    let debounce = Duration::from_millis(100);
+    fs::create_dir_all(format!("{source_path}/d4/d4d1")).ok();
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    fs::rename(format!("{source_path}/d2"), format!("{source_path}/d2r")).ok();
+    let mut changes = watcher.wait_for_changes(wait, debounce).unwrap().paths;
+    assert_eq!(BTreeSet::from(["d2", "d2r", "d4", "f1"].map(String::from)), changes);
+
+    //directories created and renamed while watching are watched as well
+    fs::write(format!("{source_path}/d4/d4d1/f"), [1]).ok();
+    fs::write(format!("{source_path}/d2r/d2d1/f"), [1]).ok();
+    let more_changes = watcher.wait_for_changes(wait, debounce).unwrap().paths;
+    assert_eq!(BTreeSet::from(["d2r/d2d1/f", "d4/d4d1/f"].map(String::from)), more_changes);
+    assert!(watcher.wait_for_changes(Some(debounce), debounce).unwrap().paths.is_empty());
+
+    //paths within directories missing in the target are copied with the directory
+    changes.extend(more_changes);
+    assert_eq!(BTreeSet::from(["d2", "d2r", "d4", "f1"].map(String::from)), affected_paths(&target_path, &changes));
+    apply_changes(&source_path, &[(target_path.as_str(), options.clone())], &changes);
+    assert!(find_differences(&source_path, &target_path, &options).is_empty());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_watch_mode_logs_each_target_to_its_own_log_file() {
+    use std::collections::BTreeSet;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let (_, second_target_path) = generate_clean_test_directory("test-env-dirs");
+    let options = SyncOptions { log_file: true, ..SyncOptions::default() };
+    //the only test installing the logger
+    crate::logging::init(&options);
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    let targets = [(target_path.as_str(), options.clone()), (second_target_path.as_str(), options.clone())];
+    crate::watch::apply_changes(&source_path, &targets, &BTreeSet::from(["f1".to_string()]));
+
+    let log = |target: &str| fs::read_to_string(crate::state::state_dir(target).join(crate::logging::LOG_FILE_NAME)).unwrap();
+    assert!(log(&target_path).contains(&format!("Backup directory \"{target_path}\"")));
+    assert!(!log(&target_path).contains(&format!("Backup directory \"{second_target_path}\"")));
+    assert!(log(&second_target_path).contains(&format!("Backup directory \"{second_target_path}\"")));
+    assert!(!log(&second_target_path).contains(&format!("Backup directory \"{target_path}\"")));
+}
+
+/// Time of the daemon tests, which only passes when they sleep.
+#[cfg(test)]
+struct FakeClock(std::cell::Cell<std::time::SystemTime>);
+
+#[cfg(test)]
+impl crate::daemon::Clock for FakeClock {
+    fn now(&self) -> std::time::SystemTime {
+        self.0.get()
+    }
+    fn sleep_until(&self, time: std::time::SystemTime) {
+        self.0.set(self.0.get().max(time));
+    }
+}
+
+#[test]
+fn test_daemon_schedules_skip_overlapping_runs_and_back_off() {
+    use std::cell::Cell;
+    use std::time::{Duration, SystemTime};
+    use chrono::{Local, TimeZone};
+    use crate::daemon::{backoff, run_scheduled, Clock, Schedule, Scheduler};
+    let minutes = |m: u64| Duration::from_secs(m * 60);
+
+    //friday noon, local time
+    let friday = SystemTime::from(Local.with_ymd_and_hms(2024, 1, 5, 12, 0, 0).unwrap());
+    let weekdays = Schedule::parse_cron("cron", "30 3 * * 1-5").unwrap();
+    assert_eq!(Some(SystemTime::from(Local.with_ymd_and_hms(2024, 1, 8, 3, 30, 0).unwrap())), weekdays.next_after(friday));
+    let sundays = Schedule::parse_cron("cron", "0 0 * * 0").unwrap();
+    assert_eq!(Some(SystemTime::from(Local.with_ymd_and_hms(2024, 1, 7, 0, 0, 0).unwrap())), sundays.next_after(friday));
+    let every_six_hours = Schedule::parse_cron("cron", "0 */6 * * *").unwrap();
+    assert_eq!(Some(friday + minutes(6 * 60)), every_six_hours.next_after(friday));
+    assert!(Schedule::parse_cron("cron", "* * *").is_err());
+    assert!(Schedule::parse_cron("cron", "61 * * * *").is_err());
+    assert!(Schedule::parse_cron("cron", "0 0 * * 8").is_err());
+    //friday to sunday, wrapping around to the cron crate's first day
+    let weekend = Schedule::parse_cron("cron", "0 0 * * 5-7").unwrap();
+    let saturday = SystemTime::from(Local.with_ymd_and_hms(2024, 1, 6, 0, 0, 0).unwrap());
+    assert_eq!(Some(saturday), weekend.next_after(friday));
+    assert_eq!(Some(saturday + minutes(24 * 60)), weekend.next_after(saturday));
+    assert_eq!(Some(SystemTime::from(Local.with_ymd_and_hms(2024, 1, 12, 0, 0, 0).unwrap())), weekend.next_after(saturday + minutes(24 * 60)));
+
+    //a run taking longer than the interval skips the runs due meanwhile
+    let clock = FakeClock(Cell::new(friday));
+    let mut scheduler = Scheduler::new(vec![("hourly".to_string(), Schedule::Interval(minutes(60)))], friday);
+    let mut started = Vec::new();
+    let durations = [minutes(10), minutes(150), minutes(0), minutes(0)];
+    run_scheduled(&mut scheduler, &clock, Some(friday + minutes(5 * 60)), &mut |_| {
+        started.push(clock.now().duration_since(friday).unwrap());
+        clock.sleep_until(clock.now() + durations[started.len() - 1]);
+        true
+    });
+    assert_eq!(vec![minutes(0), minutes(60), minutes(240), minutes(300)], started);
+
+    //failed runs are retried after a growing backoff, a successful run ends it
+    let clock = FakeClock(Cell::new(friday));
+    let mut scheduler = Scheduler::new(vec![("often".to_string(), Schedule::Interval(minutes(1)))], friday);
+    let mut started = Vec::new();
+    run_scheduled(&mut scheduler, &clock, Some(friday + minutes(20)), &mut |_| {
+        started.push(clock.now().duration_since(friday).unwrap());
+        started.len() >= 5
+    });
+    assert_eq!(vec![minutes(0), minutes(1), minutes(3), minutes(7), minutes(15), minutes(16), minutes(17), minutes(18), minutes(19), minutes(20)], started);
+    assert_eq!((minutes(1), minutes(8), minutes(6 * 60)), (backoff(1), backoff(4), backoff(100)));
+}
+
+#[test]
+fn test_daemon_run_reports() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    let config = crate::config::parse_config(&format!(r#"
+        [profiles.nightly]
+        source = "{source_path}"
+        target = "{target_path}"
+        cron = "0 3 * * *"
+    "#)).unwrap();
+    let profile = &config.profiles["nightly"];
+    assert!(matches!(profile.schedule, Some(crate::daemon::Schedule::Cron(_))));
+    assert!(crate::config::parse_config("[profiles.p]\nsource = \"s\"\ntarget = \"t\"\ninterval = \"1h\"\ncron = \"0 3 * * *\"").is_err());
+    assert!(crate::config::parse_config("[profiles.p]\nsource = \"s\"\ntarget = \"t\"\ninterval = \"0s\"").is_err());
+
+    let started = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    assert!(crate::run_scheduled_profile("nightly", profile, &SyncOptions::default(), &FakeClock(std::cell::Cell::new(started))));
+    assert!(find_differences(&source_path, &target_path, &SyncOptions::default()).is_empty());
+    let reports: Vec<_> = fs::read_dir(crate::daemon::reports_dir(&target_path)).unwrap().map(|e| e.unwrap().path()).collect();
+    assert_eq!(1, reports.len());
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&reports[0]).unwrap()).unwrap();
+    assert_eq!(("nightly", 0, 1, 5), (report["profile"].as_str().unwrap(), report["exit_code"].as_i64().unwrap(), report["operations"].as_u64().unwrap(), report["bytes_written"].as_u64().unwrap()));
+    assert_eq!(("2023-11-14T22:13:20Z", "2023-11-14T22:13:20Z"), (report["started"].as_str().unwrap(), report["finished"].as_str().unwrap()));
+
+    //a missing target fails the run, the report cannot be written there
+    let missing = crate::config::parse_config(r#"
+        [profiles.missing]
+        source = "test-env-dirs"
+        target = "test-env-dirs/does-not-exist"
+        interval = "1h"
+    "#).unwrap();
+    assert!(!crate::run_scheduled_profile("missing", &missing.profiles["missing"], &SyncOptions::default(), &crate::daemon::SystemClock));
+}
+
+#[test]
+fn test_hooks_get_the_context_of_a_sync() {
+    use crate::cli::Mode;
+    use crate::outcome::Outcome;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    let (options, log) = recording_hooks();
+    assert_eq!(Outcome::Success, crate::synchronize_target(Mode::Sync, &source_path, &target_path, &options, &Default::default()));
+    assert_eq!("pre-scan sync\npost-scan 1 0\npre-apply 1\npost-apply 0 1 0\n", fs::read_to_string(&log).unwrap());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+fn test_post_apply_hook_runs_when_nothing_is_applied() {
+    use crate::cli::Mode;
+    use crate::outcome::Outcome;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let (options, log) = recording_hooks();
+    assert_eq!(Outcome::NoChanges, crate::synchronize_target(Mode::Sync, &source_path, &target_path, &options, &Default::default()));
+    assert_eq!("pre-scan sync\npost-scan 0 0\npost-apply 3 0 0\n", fs::read_to_string(&log).unwrap());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+fn test_failing_pre_apply_hook_aborts_and_runs_the_post_apply_hook() {
+    use crate::cli::Mode;
+    use crate::outcome::Outcome;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f2"), [5,4,3,2,1]).ok();
+    let (options, log) = recording_hooks();
+    let failing = options_from_args(&["--pre-apply-hook", "false", "--post-scan-hook", ""], options.clone());
+    assert_eq!(Outcome::Failed, crate::synchronize_target(Mode::Sync, &source_path, &target_path, &failing, &Default::default()));
+    assert_eq!("pre-scan sync\npost-apply 1 0 0\n", fs::read_to_string(&log).unwrap());
+    assert_eq!(1, find_differences(&source_path, &target_path, &options).len());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+fn test_uis_run_the_hooks() {
+    use crate::cli::Mode;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f2"), [5,4,3,2,1]).ok();
+    let (options, log) = recording_hooks();
+    let analysis = crate::selection::analyze(&source_path, &target_path, &options, Mode::Ui).unwrap();
+    crate::selection::apply(&source_path, &target_path, &analysis.differences, &options, Mode::Ui).unwrap();
+    assert_eq!("pre-scan ui\npost-scan 1 0\npre-apply 1\npost-apply 0 1 0\n", fs::read_to_string(&log).unwrap());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+fn test_failing_pre_apply_hook_of_the_uis_runs_the_post_apply_hook() {
+    use crate::cli::Mode;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f2"), [5,4,3,2,1]).ok();
+    let differences = find_differences(&source_path, &target_path, &SyncOptions::default());
+    let (options, log) = recording_hooks();
+    let failing = options_from_args(&["--pre-apply-hook", "false"], options);
+    assert!(crate::selection::apply(&source_path, &target_path, &differences, &failing, Mode::Tui).is_err());
+    assert_eq!("post-apply 1 0 0\n", fs::read_to_string(&log).unwrap());
+    assert_eq!(1, find_differences(&source_path, &target_path, &SyncOptions::default()).len());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_watch_mode_runs_the_hooks() {
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f2"), [5,4,3,2,1]).ok();
+    let (options, log) = recording_hooks();
+    let changes = std::collections::BTreeSet::from(["f2".to_string()]);
+    crate::watch::apply_changes(&source_path, &[(target_path.as_str(), options)], &changes);
+    assert_eq!("pre-scan watch\npost-scan 1 0\npre-apply 1\npost-apply 0 1 0\n", fs::read_to_string(&log).unwrap());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+fn test_failing_pre_scan_hook_aborts_and_runs_the_post_apply_hook() {
+    use crate::cli::Mode;
+    use crate::outcome::Outcome;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    let (options, log) = failing_pre_scan_hook();
+    assert_eq!(Outcome::Failed, crate::synchronize_target(Mode::Sync, &source_path, &target_path, &options, &Default::default()));
+    assert_eq!(format!("pre-scan {target_path}\npost-apply 1\n"), fs::read_to_string(&log).unwrap());
+    assert_eq!(1, find_differences(&source_path, &target_path, &SyncOptions::default()).len());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+fn test_failing_pre_scan_hook_of_a_read_only_run_runs_no_post_apply_hook() {
+    use crate::cli::Mode;
+    use crate::outcome::Outcome;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let (options, log) = failing_pre_scan_hook();
+    assert_eq!(Outcome::Failed, crate::synchronize_target(Mode::Analyze, &source_path, &target_path, &options, &Default::default()));
+    assert_eq!(format!("pre-scan {target_path}\n"), fs::read_to_string(&log).unwrap());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+fn test_read_only_runs_neither_lock_the_target_nor_create_its_state_directory() {
+    use crate::cli::Mode;
+    use crate::outcome::Outcome;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    assert_eq!(Outcome::NoChanges, crate::synchronize_target(Mode::Analyze, &source_path, &target_path, &SyncOptions::default(), &Default::default()));
+    assert_eq!(Outcome::Success, crate::synchronize_target(Mode::Verify, &source_path, &target_path, &SyncOptions::default(), &Default::default()));
+    assert!(!fs::exists(crate::state::state_dir(&target_path)).unwrap());
+
+    let _lock = crate::state::TargetLock::acquire(&target_path).unwrap();
+    assert_eq!(Outcome::NoChanges, crate::synchronize_target(Mode::Analyze, &source_path, &target_path, &SyncOptions::default(), &Default::default()));
+    assert_eq!(Outcome::Failed, crate::synchronize_target(Mode::Sync, &source_path, &target_path, &SyncOptions::default(), &Default::default()));
+}
+
+#[test]
+fn test_failing_pre_scan_hook_of_a_scheduled_run_runs_the_post_apply_hook() {
+    use crate::outcome::Outcome;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let (options, log) = failing_pre_scan_hook();
+    assert_eq!(Outcome::Failed, crate::scheduled_sync(&source_path, &target_path, &options, &Default::default()).0);
+    assert_eq!(format!("pre-scan {target_path}\npost-apply 1\n"), fs::read_to_string(&log).unwrap());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+fn test_failing_pre_scan_hook_of_the_uis_runs_the_post_apply_hook() {
+    use crate::cli::Mode;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    let (options, log) = failing_pre_scan_hook();
+    assert!(crate::selection::analyze(&source_path, &target_path, &options, Mode::Ui).is_err());
+    assert_eq!(format!("pre-scan {target_path}\npost-apply 1\n"), fs::read_to_string(&log).unwrap());
+    fs::remove_file(&log).ok();
+}
+
+#[test]
+fn test_dialogue_decides_for_each_difference() {
+    use crate::dialogue::decide_each;
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    fs::write(format!("{source_path}/d1/d1f3"), [5,4,3,2,1]).ok();
+    fs::write(format!("{target_path}/f2"), [5,4,3,2,1]).ok();
+    let mut diffs = find_differences(&source_path, &target_path, &SyncOptions::default());
+    let problems = verify_source_fully_newer_than_target(&diffs, &SyncOptions::default());
+    assert_eq!((3, 1), (diffs.len(), problems.len()));
+    //the difference with the problem last
+    diffs.sort_by_key(|d| problems.contains_key(d));
+    let decide = |input: &str| {
+        let mut output = Vec::new();
+        let decisions = decide_each(&diffs, &problems, &source_path, &target_path, &mut input.as_bytes(), &mut output);
+        (decisions, String::from_utf8(output).unwrap())
+    };
+
+    let (decisions, output) = decide("d\ny\nn\nk\n");
+    assert_eq!(Some(vec![true, false, false]), decisions);
+    assert!(output.contains("    source: \"") && output.contains("[3/3] ") && output.contains("[t] take source, [k] keep backup"));
+    //problems are decided on with take source or keep backup, also after applying all remaining
+    let (decisions, output) = decide("a\ny\nx\nt\n");
+    assert_eq!(Some(vec![true, true, true]), decisions);
+    assert_eq!(2, output.matches("Please answer with one of the letters in brackets.").count());
+    assert_eq!(Some(vec![false, false, false]), decide("s\n").0);
+    assert_eq!(Some(vec![true, false, false]), decide("y\ns\n").0);
+    //quitting or ending the input applies nothing
+    assert_eq!(None, decide("y\nq\n").0);
+    assert_eq!(None, decide("y\n").0);
+}
+
+#[test]
+fn test_selection_filters_and_applies_selected_differences() {
+    use crate::selection::{Filter, Selection};
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    fs::write(format!("{source_path}/d1/d1f3"), [5,4,3,2,1]).ok();
+    fs::write(format!("{target_path}/f2"), [5,4,3,2,1]).ok();
+    let mut selection = Selection::new(source_path.clone(), target_path.clone(), SyncOptions::default(), Vec::new(), crate::cli::Mode::Ui);
+    selection.re_run_analysis();
+    //differences with problems are not selected
+    assert_eq!((3, 2), (selection.selected_differences.len(), selection.selected_count()));
+
+    let problems_only = Filter { problems_only: true, ..Filter::default() };
+    assert_eq!(1, selection.matching(&problems_only).len());
+    let in_d1 = Filter { text: "D1/".to_string(), ..Filter::default() };
+    let matching = selection.matching(&in_d1);
+    assert_eq!(1, matching.len());
+    assert!(selection.selected_differences[matching[0]].0.describe_short().contains("d1f3"));
+    assert_eq!(3, selection.matching(&Filter::default()).len());
+
+    selection.set_selected(matching[0], false);
+    let report = selection.apply_selected_changes().unwrap();
+    assert_eq!((1, 0), (report.results.len(), report.failed()));
+    //analyzed again after applying
+    assert_eq!(2, selection.selected_differences.len());
+
+    let _lock = crate::state::TargetLock::acquire(&target_path).unwrap();
+    assert!(selection.apply_selected_changes().is_none());
+    assert!(selection.status().is_some());
+}
+
+#[test]
+fn test_progress_is_counted_and_cancelling_stops_between_operations() {
+    use std::sync::Arc;
+    use crate::progress::Progress;
+    use crate::selection::{analyze, apply};
+    let (source_path, target_path) = generate_clean_test_directory("test-env-dirs");
+    fs::write(format!("{source_path}/f1"), [5,4,3,2,1]).ok();
+    fs::write(format!("{source_path}/d1/d1f3"), [5,4,3,2,1]).ok();
+    let progress = Arc::new(Progress::default());
+    let options = SyncOptions { workers: 1, progress: Some(progress.clone()), ..SyncOptions::default() };
+    let analysis = analyze(&source_path, &target_path, &options, crate::cli::Mode::Ui).unwrap();
+    assert_eq!(2, analysis.differences.len());
+    assert!(progress.snapshot().directories_scanned > 0);
+    assert_eq!(None, progress.snapshot().fraction());
+
+    let report = apply(&source_path, &target_path, &analysis.differences, &options, crate::cli::Mode::Ui).unwrap();
+    let snapshot = progress.snapshot();
+    assert_eq!((2, 2, 10), (snapshot.operations_planned, snapshot.operations_executed, snapshot.bytes_written));
+    assert_eq!(2, report.results.len());
+
+    //once cancelled, neither directories are scanned nor operations executed
+    fs::write(format!("{source_path}/f1"), [1,2,3,4,5,6]).ok();
+    fs::write(format!("{source_path}/d1/d1f3"), [1,2,3,4,5,6]).ok();
+    let cancelled = Arc::new(Progress::default());
+    cancelled.cancel();
+    let options = SyncOptions { progress: Some(cancelled.clone()), ..options };
+    assert!(analyze(&source_path, &target_path, &options, crate::cli::Mode::Ui).unwrap().differences.is_empty());
+    let differences = find_differences(&source_path, &target_path, &SyncOptions::default());
+    assert_eq!(2, differences.len());
+    let report = apply(&source_path, &target_path, &differences, &options, crate::cli::Mode::Ui).unwrap();
+    assert!(report.results.is_empty());
+    assert_eq!(0, cancelled.snapshot().operations_executed);
+    assert_eq!(2, find_differences(&source_path, &target_path, &SyncOptions::default()).len());
+}
+
+#[test]
+fn test_profiles_of_the_config_file() {
+    use crate::cli::Mode;
+    let config = crate::config::parse_config(r#"
+        retention = 3
+
+        [profiles.photos]
+        source = "/home/user/photos"
+        target = "/media/backup/photos"
+        mode = "analyze"
+        excludes = ["*.tmp"]
+        comparison = "checksum"
+
+        [profiles.docs]
+        source = "docs"
+        targets = ["backup/docs", "/media/nas/docs"]
+    "#).unwrap();
+    assert_eq!(2, config.profiles.len());
+    let photos = &config.profiles["photos"];
+    assert_eq!(("/home/user/photos", "/media/backup/photos", Mode::Analyze), (photos.source.as_str(), photos.targets[0].as_str(), photos.mode));
+    assert_eq!((Mode::Sync, 2), (config.profiles["docs"].mode, config.profiles["docs"].targets.len()));
+}
+
+#[test]
+fn test_profile_options_override_those_of_the_config_file() {
+    let config = crate::config::parse_config(r#"
+        retention = 3
+        comparison = "mtime"
+
+        [profiles.photos]
+        source = "/home/user/photos"
+        target = "/media/backup/photos"
+        excludes = ["*.tmp"]
+        comparison = "checksum"
+    "#).unwrap();
+    let mut options = SyncOptions::default();
+    config.apply_to(&mut options).unwrap();
+    config.profiles["photos"].options.apply_to(&mut options).unwrap();
+    assert_eq!(Some(3), options.retention);
+    assert_eq!(crate::options::Comparison::Checksum, options.comparison);
+    assert!(options.excludes.is_excluded("a/b.tmp", false));
+}
+
+#[test]
+fn test_invalid_profiles_are_rejected() {
+    assert!(crate::config::parse_config("[profiles.p]\ntarget = \"t\"").is_err());
+    assert!(crate::config::parse_config("[profiles.p]\nsource = \"s\"\ntargets = []").is_err());
+    assert!(crate::config::parse_config("[profiles.p]\nsource = \"s\"\ntarget = \"t\"\nunknown = 1").is_err());
+    assert!(crate::config::parse_config("[profiles.p]\nsource = \"s\"\ntarget = \"t\"\nmode = \"later\"").is_err());
+}
+
+#[test]
+fn test_empty_hook_of_a_profile_disables_the_one_of_the_config_file() {
+    let config = crate::config::parse_config("pre_scan_hook = \"true\"\n[profiles.p]\nsource = \"s\"\ntarget = \"t\"\npre_scan_hook = \"\"").unwrap();
+    let mut options = SyncOptions::default();
+    config.apply_to(&mut options).unwrap();
+    assert_eq!(Some("true"), options.hooks.pre_scan.as_deref());
+    config.profiles["p"].options.apply_to(&mut options).unwrap();
+    assert_eq!(None, options.hooks.pre_scan);
+}
+
 
 
 //Wrongly detected problems::
@@ -186,14 +1292,49 @@ fn generate_clean_test_directory(path: &str) -> (String, String) {
     return (source_path, target_path);
 }
 
+/// Options with hooks that append their name and context to the returned log file.
+#[cfg(test)]
+fn recording_hooks() -> (SyncOptions, String) {
+    let log = format!("test-env-dirs/hooks_{}.log", random::<u64>());
+    let record = |fields: &str| format!("echo $DIRSYNC_HOOK {fields} >> {log}");
+    let options = options_from_args(&[
+        "--pre-scan-hook", &record("$DIRSYNC_MODE"),
+        "--post-scan-hook", &record("$DIRSYNC_DIFFERENCES $DIRSYNC_PROBLEMS"),
+        "--pre-apply-hook", &record("$DIRSYNC_DIFFERENCES"),
+        "--post-apply-hook", &record("$DIRSYNC_EXIT_CODE $DIRSYNC_OPERATIONS $DIRSYNC_FAILED"),
+    ], SyncOptions::default());
+    (options, log)
+}
+
+/// Options with a pre-scan hook that fails, both it and the post-apply hook append to the returned log file.
+#[cfg(test)]
+fn failing_pre_scan_hook() -> (SyncOptions, String) {
+    let log = format!("test-env-dirs/hooks_{}.log", random::<u64>());
+    let options = options_from_args(&[
+        "--pre-scan-hook", &format!("echo $DIRSYNC_HOOK $DIRSYNC_TARGET >> {log} && false"),
+        "--post-apply-hook", &format!("echo $DIRSYNC_HOOK $DIRSYNC_EXIT_CODE >> {log}"),
+    ], SyncOptions::default());
+    (options, log)
+}
+
+/// Options of a sync command line with the given options.
+#[cfg(test)]
+fn options_from_args(args: &[&str], mut options: SyncOptions) -> SyncOptions {
+    let command_line = ["directory_synchronizer", "sync", "source", "target"].iter().chain(args).copied();
+    let cli = crate::cli::try_parse(command_line).unwrap();
+    cli.command.run_args().unwrap().1.options.apply_to(&mut options);
+    options
+}
+
 fn run_synchronization_as_test(source_path: &str, target_path: &str, problems_assumed_empty: bool) {
-    let diffs = find_differences(source_path, target_path);
+    let diffs = find_differences(source_path, target_path, &SyncOptions::default());
     println!("diffs: {:?}", diffs);
-    let problems = verify_source_fully_newer_than_target(&diffs);
+    let problems = verify_source_fully_newer_than_target(&diffs, &SyncOptions::default());
     println!("problems: {:?}", problems);
     assert_eq!(problems_assumed_empty, problems.is_empty());
-    apply_diffs_source_to_target_with_prints(source_path, target_path, diffs.iter());
+    let report = apply_diffs_source_to_target_with_prints(source_path, target_path, diffs.iter(), &SyncOptions::default());
+    assert_eq!(0, report.failed());
 
-    let diffs = find_differences(source_path, target_path);
+    let diffs = find_differences(source_path, target_path, &SyncOptions::default());
     assert!(diffs.is_empty());
 }
\ No newline at end of file
@@ -0,0 +1,60 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits the rate of bytes written and files touched, shared by all workers of a run.
+pub(crate) struct Throttle {
+    bytes: Option<Pace>,
+    files: Option<Pace>,
+}
+
+/// Hands out time slots at a fixed rate. Each consumer reserves the slot following the last reservation
+/// and sleeps until the end of its slot, so that concurrent consumers together never exceed the rate.
+struct Pace {
+    per_second: f64,
+    next_free: Mutex<Option<Instant>>,
+}
+
+impl Pace {
+    fn new(per_second: f64) -> Pace {
+        Pace { per_second, next_free: Mutex::new(None) }
+    }
+
+    fn take(&self, amount: f64) {
+        let now = Instant::now();
+        let slot_end = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let slot_start = next_free.map_or(now, |n| n.max(now));
+            let slot_end = slot_start + Duration::from_secs_f64(amount / self.per_second);
+            *next_free = Some(slot_end);
+            slot_end
+        };
+        let wait = slot_end.saturating_duration_since(now);
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl Throttle {
+    pub(crate) fn new(max_bytes_per_second: Option<u64>, max_files_per_second: Option<f64>) -> Throttle {
+        Throttle {
+            bytes: max_bytes_per_second.filter(|&b| b > 0).map(|b| Pace::new(b as f64)),
+            files: max_files_per_second.filter(|&f| f > 0.0).map(Pace::new),
+        }
+    }
+
+    /// Blocks until `len` more bytes may be written.
+    pub(crate) fn consume_bytes(&self, len: usize) {
+        if let Some(pace) = &self.bytes {
+            pace.take(len as f64);
+        }
+    }
+
+    /// Blocks until one more file may be touched.
+    pub(crate) fn consume_file(&self) {
+        if let Some(pace) = &self.files {
+            pace.take(1.0);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use filetime::{FileTime, set_file_mtime};
+
+/// Timestamp granularities of common filesystems: ext4/btrfs/xfs, NTFS, some network filesystems, SMB/ext3, FAT/exFAT.
+const GRANULARITIES: [Duration; 5] = [
+    Duration::ZERO,
+    Duration::from_nanos(100),
+    Duration::from_millis(1),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+];
+
+/// Whether two modification times are equal, allowing them to differ by at most the tolerance.
+pub(crate) fn mtimes_equal(a: SystemTime, b: SystemTime, tolerance: Duration) -> bool {
+    distance(a, b) <= tolerance
+}
+
+/// Whether `a` is newer than `b` by more than the tolerance.
+pub(crate) fn is_newer(a: SystemTime, b: SystemTime, tolerance: Duration) -> bool {
+    a > b && distance(a, b) > tolerance
+}
+
+fn distance(a: SystemTime, b: SystemTime) -> Duration {
+    a.duration_since(b).or_else(|_| b.duration_since(a)).unwrap_or_default()
+}
+
+/// Determines the timestamp precision of the filesystem the directory is on,
+/// by writing a file with an odd second and maximal sub second part as modification time and reading it back.
+pub(crate) fn probe_mtime_granularity(dir: &Path) -> io::Result<Duration> {
+    let probe = dir.join(".directory_synchronizer-mtime-probe");
+    fs::write(&probe, [])?;
+    let written = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_001, 999_999_999);
+    let read = set_file_mtime(&probe, FileTime::from(written)).and_then(|_| fs::metadata(&probe)?.modified());
+    fs::remove_file(&probe)?;
+
+    let error = distance(written, read?);
+    Ok(GRANULARITIES.into_iter().find(|&g| g >= error).unwrap_or(error))
+}
@@ -0,0 +1,290 @@
+use std::io;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::{DefaultTerminal, Frame};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use crate::cli::Mode;
+use crate::dialogue;
+use crate::logging;
+use crate::options::{OutputFormat, SyncOptions};
+use crate::selection::{Filter, Selection, UiProfile};
+
+/// Number of console messages kept for the messages pane.
+const MESSAGES_KEPT: usize = 500;
+
+/// Starts the terminal ui, which offers what the iced ui does for terminals without a display (e.g. over ssh).
+/// Console messages are shown in the ui instead of being printed, until it is closed.
+pub(crate) fn start_terminal_ui(source_path: String, target_path: String, mut options: SyncOptions, profiles: Vec<UiProfile>) -> io::Result<()> {
+    //json records would be written over the ui
+    options.output_format = OutputFormat::Text;
+    let mut ui = TerminalUi {
+        selection: Selection::new(source_path, target_path, options, profiles, Mode::Tui),
+        filter: Filter::default(),
+        visible: Vec::new(),
+        list: ListState::default(),
+        input: Input::Normal,
+        show_details: true,
+        messages: Vec::new(),
+        busy: None,
+    };
+    logging::capture_console(true);
+    let mut terminal = ratatui::init();
+    let result = ui.run(&mut terminal);
+    ratatui::restore();
+    logging::capture_console(false);
+    result
+}
+
+/// What the keys currently do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Input {
+    Normal,
+    /// Typed characters edit the filter text.
+    Filter,
+    /// Waits for the confirmation to apply the selected differences.
+    ConfirmApply,
+}
+
+struct TerminalUi {
+    selection: Selection,
+    filter: Filter,
+    /// Indices of the differences the filter shows.
+    visible: Vec<usize>,
+    /// The highlighted line of the visible differences.
+    list: ListState,
+    input: Input,
+    show_details: bool,
+    /// Console messages of the analyses and applications, the newest last.
+    messages: Vec<String>,
+    /// Shown while an analysis or application blocks the ui.
+    busy: Option<&'static str>,
+}
+
+impl TerminalUi {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            self.collect_messages();
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else { continue };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let keep_running = match self.input {
+                Input::Normal => self.handle_key(key, terminal)?,
+                Input::Filter => {
+                    self.edit_filter(key);
+                    true
+                }
+                Input::ConfirmApply => {
+                    self.input = Input::Normal;
+                    if key.code == KeyCode::Char('y') {
+                        self.while_busy(terminal, "Applying the selected differences...", |selection| {
+                            if let Some(report) = selection.apply_selected_changes() {
+                                log::info!("{}", report.summary());
+                            }
+                        })?;
+                    }
+                    true
+                }
+            };
+            if !keep_running {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns whether the ui keeps running.
+    fn handle_key(&mut self, key: KeyEvent, terminal: &mut DefaultTerminal) -> io::Result<bool> {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Down | KeyCode::Char('j') => self.move_cursor(1),
+            KeyCode::Up | KeyCode::Char('k') => self.move_cursor(-1),
+            KeyCode::PageDown => self.move_cursor(10),
+            KeyCode::PageUp => self.move_cursor(-10),
+            KeyCode::Home | KeyCode::Char('g') => self.move_cursor(isize::MIN),
+            KeyCode::End | KeyCode::Char('G') => self.move_cursor(isize::MAX),
+            KeyCode::Char(' ') => {
+                if let Some(i) = self.current() {
+                    let selected = self.selection.selected_differences[i].1;
+                    self.selection.set_selected(i, !selected);
+                }
+            }
+            KeyCode::Char(c @ ('a' | 'n')) => {
+                for &i in &self.visible {
+                    self.selection.set_selected(i, c == 'a');
+                }
+            }
+            KeyCode::Char('/') => self.input = Input::Filter,
+            KeyCode::Char('p') => {
+                self.filter.problems_only = !self.filter.problems_only;
+                self.refresh();
+            }
+            KeyCode::Char('d') | KeyCode::Tab => self.show_details = !self.show_details,
+            KeyCode::Char('r') => self.while_busy(terminal, "Analyzing...", Selection::re_run_analysis)?,
+            KeyCode::Char('x') if self.selection.selected_count() > 0 => self.input = Input::ConfirmApply,
+            KeyCode::Char('x') => self.messages.push("Nothing selected.".to_string()),
+            KeyCode::Char('P') => self.next_profile(),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn edit_filter(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Char(c) => self.filter.text.push(c),
+            KeyCode::Backspace => {
+                self.filter.text.pop();
+            }
+            KeyCode::Esc => {
+                self.filter.text.clear();
+                self.input = Input::Normal;
+            }
+            KeyCode::Enter => self.input = Input::Normal,
+            _ => {}
+        }
+        self.refresh();
+    }
+
+    /// Adds the messages logged since the last event, dropping the oldest ones.
+    fn collect_messages(&mut self) {
+        self.messages.extend(logging::take_captured());
+        let outdated = self.messages.len().saturating_sub(MESSAGES_KEPT);
+        self.messages.drain(..outdated);
+    }
+
+    /// Runs the action after showing that the ui is busy, the messages it logs are shown afterwards.
+    fn while_busy(&mut self, terminal: &mut DefaultTerminal, what: &'static str, action: impl FnOnce(&mut Selection)) -> io::Result<()> {
+        self.busy = Some(what);
+        terminal.draw(|frame| self.draw(frame))?;
+        action(&mut self.selection);
+        self.busy = None;
+        self.refresh();
+        Ok(())
+    }
+
+    fn next_profile(&mut self) {
+        let profiles = &self.selection.profiles;
+        if profiles.is_empty() {
+            self.messages.push("The config file has no profiles.".to_string());
+            return;
+        }
+        let next = match profiles.iter().position(|p| Some(&p.name) == self.selection.selected_profile.as_ref()) {
+            Some(i) => (i + 1) % profiles.len(),
+            None => 0,
+        };
+        let name = profiles[next].name.clone();
+        self.selection.select_profile(name.clone());
+        self.messages.push(format!("Profile \"{name}\" selected, press r to analyze."));
+        self.refresh();
+    }
+
+    /// Applies the filter again, keeping the highlighted line within the visible differences.
+    fn refresh(&mut self) {
+        self.visible = self.selection.matching(&self.filter);
+        let highlighted = match (self.list.selected(), self.visible.len()) {
+            (_, 0) => None,
+            (Some(i), len) => Some(i.min(len - 1)),
+            (None, _) => Some(0),
+        };
+        self.list.select(highlighted);
+    }
+
+    fn move_cursor(&mut self, delta: isize) {
+        let Some(highlighted) = self.list.selected() else { return };
+        let last = self.visible.len().saturating_sub(1);
+        self.list.select(Some(highlighted.saturating_add_signed(delta).min(last)));
+    }
+
+    /// Index of the highlighted difference.
+    fn current(&self) -> Option<usize> {
+        self.list.selected().and_then(|i| self.visible.get(i)).copied()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, filter, body, messages, help] = Layout::vertical([
+            Constraint::Length(1), Constraint::Length(1), Constraint::Min(5), Constraint::Length(7), Constraint::Length(1),
+        ]).areas(frame.area());
+
+        let mut title = vec![
+            Span::from(self.selection.source_path.as_str()).bold(),
+            Span::from(" --> "),
+            Span::from(self.selection.target_path.as_str()).bold(),
+        ];
+        if let Some(profile) = &self.selection.selected_profile {
+            title.push(Span::from(format!("  (profile {profile})")));
+        }
+        frame.render_widget(Line::from(title), header);
+
+        let cursor = if self.input == Input::Filter { "_" } else { "" };
+        frame.render_widget(Line::from(format!("Filter: {}{cursor}{}    {} of {} shown, {} selected",
+            self.filter.text, if self.filter.problems_only { " [problems only]" } else { "" },
+            self.visible.len(), self.selection.selected_differences.len(), self.selection.selected_count())), filter);
+
+        if self.show_details {
+            let [list, details] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(body);
+            self.draw_differences(frame, list);
+            self.draw_details(frame, details);
+        } else {
+            self.draw_differences(frame, body);
+        }
+        self.draw_messages(frame, messages);
+
+        let keys = match self.input {
+            Input::Normal => "\u{2191}\u{2193} move  space select  a/n all/none  / filter  p problems only  d details  r analyze  x apply  P profile  q quit".to_string(),
+            Input::Filter => "type to filter  enter done  esc clear".to_string(),
+            Input::ConfirmApply => format!("Apply the {} selected differences to the backup directory? y/n", self.selection.selected_count()),
+        };
+        frame.render_widget(Line::from(keys).add_modifier(Modifier::REVERSED), help);
+    }
+
+    fn draw_differences(&mut self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title("Differences");
+        if self.visible.is_empty() {
+            let message = if self.selection.selected_differences.is_empty() { "No differences found, press r to analyze." } else { "No differences match the filter." };
+            frame.render_widget(Paragraph::new(message).block(block), area);
+            return;
+        }
+        let items: Vec<ListItem> = self.visible.iter().map(|&i| {
+            let (d, selected) = &self.selection.selected_differences[i];
+            let line = format!("[{}] {}  in \"{}\"", if *selected { "x" } else { " " }, d.describe_short(), self.selection.directory_of(d));
+            match self.selection.problems.contains_key(d) {
+                true => ListItem::new(line).fg(Color::Red),
+                false => ListItem::new(line),
+            }
+        }).collect();
+        let list = List::new(items).block(block).highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, area, &mut self.list);
+    }
+
+    fn draw_details(&self, frame: &mut Frame, area: Rect) {
+        let mut text = Text::default();
+        if let Some(i) = self.current() {
+            let d = &self.selection.selected_differences[i].0;
+            text.push_line(d.describe());
+            text.push_line(format!("    in directory: \"{}\"", self.selection.directory_of(d)));
+            if let Some(problem) = self.selection.problems.get(d) {
+                text.push_line(Line::from(format!("    Problem: {problem}")).fg(Color::Red));
+            }
+            for line in dialogue::details(d).lines() {
+                text.push_line(line.to_string());
+            }
+        }
+        frame.render_widget(Paragraph::new(text).wrap(Wrap { trim: false }).block(Block::bordered().title("Details")), area);
+    }
+
+    fn draw_messages(&self, frame: &mut Frame, area: Rect) {
+        let mut lines: Vec<Line> = Vec::new();
+        if let Some(status) = self.selection.status() {
+            lines.push(Line::from(status).fg(Color::Yellow));
+        }
+        if let Some(busy) = self.busy {
+            lines.push(Line::from(busy).bold());
+        }
+        let room = (area.height as usize).saturating_sub(2).saturating_sub(lines.len());
+        let newest = self.messages.iter().flat_map(|m| m.lines()).rev().take(room).collect::<Vec<_>>();
+        lines.splice(0..0, newest.into_iter().rev().map(Line::from));
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title("Messages")), area);
+    }
+}
@@ -1,53 +1,70 @@
-use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use alignment::{Alignment, Vertical};
-use iced::{Element, Application, Settings, Theme, executor, Command, Length, alignment, Font};
-use iced::widget::{button, checkbox, column, Column, container, row, scrollable, Space, text};
+use iced::{Element, Application, Settings, Subscription, Theme, executor, Command, Length, alignment, Font};
+use iced::futures::{future, StreamExt};
+use iced::futures::channel::mpsc::{self, UnboundedReceiver};
+use iced::futures::lock::Mutex;
+use iced::widget::{button, checkbox, column, Column, container, pick_list, progress_bar, row, scrollable, Space, text};
 use iced::widget::scrollable::Properties;
-use crate::differences::{apply_diffs_source_to_target_with_prints, Difference, find_differences, verify_source_fully_newer_than_target};
+use crate::apply::ApplyReport;
+use crate::cli::Mode;
+use crate::options::SyncOptions;
+use crate::progress::{Progress, ProgressSnapshot};
+use crate::selection::{analyze, apply, Analysis, Selection, UiProfile};
 
-pub(crate) fn start_synchronization_ui(source_path: String, target_path: String) -> iced::Result {
+/// How often the progress of a running analysis or application is shown.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) fn start_synchronization_ui(source_path: String, target_path: String, options: SyncOptions, profiles: Vec<UiProfile>) -> iced::Result {
     SynchronizerUI::run(Settings::with_flags( SynchronizerUiFlags { source_path,
         target_path,
+        options,
+        profiles,
     }))
 }
 
 
 struct SynchronizerUI {
-    source_path: String,
-    target_path: String,
-    selected_differences: Vec<(Difference, bool)>,
-    problems: HashMap<Difference, String>
+    selection: Selection,
+    /// The analysis or application running in the background, at most one at a time.
+    job: Option<Job>,
+    /// Identifies the subscription of the next job.
+    next_job_id: u64,
+    /// Summary of the last application, or why the last job did not complete.
+    last_result: Option<String>,
 }
 
-impl SynchronizerUI {
-    pub(crate) fn re_run_analysis(&mut self) {
-        let newly_found = find_differences(&self.source_path, &self.target_path);
-        self.selected_differences.clear();
-        self.problems = verify_source_fully_newer_than_target(&newly_found);
-        for d in newly_found {
-            let has_problem = self.problems.get(&d).is_some();
-            self.selected_differences.push((d, !has_problem));
-        }
-    }
-    pub(crate) fn apply_selected_changes(&mut self) {
-        apply_diffs_source_to_target_with_prints(
-            &self.source_path, &self.target_path,
-            self.selected_differences.iter().filter(|(_, selected)| *selected).map(|(d, _)| d)
-        );
-        self.re_run_analysis();
-    }
+/// An analysis or application running on its own thread, so that the ui stays responsive.
+struct Job {
+    id: u64,
+    description: &'static str,
+    progress: Arc<Progress>,
+    last_progress: ProgressSnapshot,
+    /// Progress while the job runs, then the message that it finished.
+    events: Arc<Mutex<UnboundedReceiver<SynchronizerUiMessage>>>,
 }
 
 struct SynchronizerUiFlags {
     source_path: String,
-    target_path: String
+    target_path: String,
+    options: SyncOptions,
+    profiles: Vec<UiProfile>,
 }
 
 #[derive(Debug, Clone)]
 enum SynchronizerUiMessage {
     CHECKBOX(bool, usize),
     AnalyzeDirectories,
-    ApplySelectedChanges
+    ApplySelectedChanges,
+    ProfileSelected(String),
+    Cancel,
+    Progress(ProgressSnapshot),
+    AnalysisFinished(Result<Analysis, String>),
+    ApplyFinished(Result<ApplyReport, String>),
+    /// The job panicked.
+    JobFailed,
 }
 
 impl Application for SynchronizerUI {
@@ -57,12 +74,14 @@ impl Application for SynchronizerUI {
     type Flags = SynchronizerUiFlags;
 
     fn new(flags: SynchronizerUiFlags) -> (SynchronizerUI, Command<Self::Message>) {
-        let r = (
-            SynchronizerUI { source_path: flags.source_path, target_path: flags.target_path, selected_differences: Vec::new(), problems: HashMap::new() },
-            Command::none(),
-        );
-        // r.0.re_run_analysis(); //blocks ui for too long
-        return r
+        let mut ui = SynchronizerUI {
+            selection: Selection::new(flags.source_path, flags.target_path, flags.options, flags.profiles, Mode::Ui),
+            job: None,
+            next_job_id: 0,
+            last_result: None,
+        };
+        ui.start_analysis();
+        (ui, Command::none())
     }
 
     fn title(&self) -> String { return String::from("Directory Synchronizer UI"); }
@@ -71,44 +90,119 @@ impl Application for SynchronizerUI {
         match message {
             // React to messages
             SynchronizerUiMessage::CHECKBOX(v, i) => {
-                self.selected_differences[i].1 = v;
+                self.selection.set_selected(i, v);
             },
             SynchronizerUiMessage::AnalyzeDirectories => {
-                self.re_run_analysis();
+                self.start_analysis();
             },
             SynchronizerUiMessage::ApplySelectedChanges => {
-                self.apply_selected_changes();
+                self.start_apply();
+            }
+            SynchronizerUiMessage::ProfileSelected(name) => {
+                if self.job.is_none() {
+                    self.selection.select_profile(name);
+                    self.last_result = None;
+                }
+            }
+            SynchronizerUiMessage::Cancel => {
+                if let Some(job) = &self.job {
+                    job.progress.cancel();
+                }
+            }
+            SynchronizerUiMessage::Progress(snapshot) => {
+                if let Some(job) = &mut self.job {
+                    job.last_progress = snapshot;
+                }
+            }
+            SynchronizerUiMessage::AnalysisFinished(analysis) => {
+                let cancelled = self.finish_job();
+                if cancelled {
+                    //a partial analysis would hide differences, the previous one is kept
+                    self.last_result = Some("Analysis cancelled.".to_string());
+                } else {
+                    self.selection.show_analysis(analysis);
+                }
+            }
+            SynchronizerUiMessage::ApplyFinished(report) => {
+                self.finish_job();
+                if let Some(report) = self.selection.show_apply(report) {
+                    self.last_result = Some(report.summary());
+                    self.start_analysis();
+                }
+            }
+            SynchronizerUiMessage::JobFailed => {
+                self.finish_job();
+                self.last_result = Some("The analysis or application failed unexpectedly, see the console for details.".to_string());
             }
         }
         Command::none()
     }
 
+    fn subscription(&self) -> Subscription<Self::Message> {
+        match &self.job {
+            None => Subscription::none(),
+            Some(job) => iced::subscription::unfold(job.id, job.events.clone(), |events| async move {
+                let next = events.lock().await.next().await;
+                match next {
+                    Some(message) => (message, events),
+                    //the job finished and its subscription ends with the next update
+                    None => future::pending().await,
+                }
+            }),
+        }
+    }
+
     fn view(&self) -> Element<Self::Message> {
         let header = row![
-            text(&self.source_path).font(Font::with_name("Monospaced")),
+            text(&self.selection.source_path).font(Font::with_name("Monospaced")),
             text(" --> ").font(Font::with_name("Monospaced")),
-            text(&self.target_path).font(Font::with_name("Monospaced")),
+            text(&self.selection.target_path).font(Font::with_name("Monospaced")),
         ].spacing(22);
 
-        let analyze = button("Analyze Directories").on_press(SynchronizerUiMessage::AnalyzeDirectories);
+        let profile_names: Vec<String> = self.selection.profiles.iter().map(|p| p.name.clone()).collect();
+        let profile_picker = if profile_names.is_empty() {
+            Element::from(Space::with_height(0))
+        } else {
+            Element::from(row![
+                text("Profile: "),
+                pick_list(profile_names, self.selection.selected_profile.clone(), SynchronizerUiMessage::ProfileSelected).placeholder("choose a profile"),
+            ].align_items(Alignment::Center))
+        };
+
+        let idle = self.job.is_none();
+        let analyze = button("Analyze Directories").on_press_maybe(idle.then_some(SynchronizerUiMessage::AnalyzeDirectories));
+
+        let apply = button("Apply selected changes").on_press_maybe(idle.then_some(SynchronizerUiMessage::ApplySelectedChanges));
 
-        let apply = button("Apply selected changes").on_press(SynchronizerUiMessage::ApplySelectedChanges);
+        let progress = match &self.job {
+            None => Element::from(text(self.last_result.clone().unwrap_or_default())),
+            Some(job) => {
+                let state = if job.progress.is_cancelled() { "Cancelling" } else { job.description };
+                let mut progress = column![
+                    text(format!("{state}... {}", job.last_progress.describe())),
+                ].spacing(5).align_items(Alignment::Center);
+                if let Some(fraction) = job.last_progress.fraction() {
+                    progress = progress.push(progress_bar(0.0..=1.0, fraction).width(Length::Fixed(400.0)));
+                }
+                Element::from(progress.push(button("Cancel").on_press_maybe((!job.progress.is_cancelled()).then_some(SynchronizerUiMessage::Cancel))))
+            }
+        };
 
-        let results = if self.selected_differences.is_empty() {
+        let results = if self.selection.selected_differences.is_empty() {
             Element::from(text("No differences found."))
         } else {
-            let mut children = Vec::with_capacity(self.selected_differences.len());
-            for (i, (d, active)) in self.selected_differences.iter().enumerate() {
+            let mut children = Vec::with_capacity(self.selection.selected_differences.len());
+            for (i, (d, active)) in self.selection.selected_differences.iter().enumerate() {
                 let checkbox =
                     checkbox("", *active, move |b| SynchronizerUiMessage::CHECKBOX(b, i));
-                match self.problems.get(d) {
+                match self.selection.problems.get(d) {
                     None => {
                         children.push(Element::from(
                             row![
                                 checkbox,
                                 column![
                                     text(d.describe_short()),
-                                    text(format!("    in directory: \"{}\"", d.get_directory_path(self.source_path.len(), self.target_path.len()))),
+                                    text(format!("    in directory: \"{}\"", d.get_directory_path(self.selection.source_path.len(), self.selection.target_path.len()))),
                                 ]
                             ].align_items(Alignment::Center)
                         ));
@@ -119,7 +213,7 @@ impl Application for SynchronizerUI {
                                 checkbox,
                                 column![
                                     text(d.describe_short()),
-                                    text(format!("    in directory: \"{}\"", d.get_directory_path(self.source_path.len(), self.target_path.len()))),
+                                    text(format!("    in directory: \"{}\"", d.get_directory_path(self.selection.source_path.len(), self.selection.target_path.len()))),
                                     text(format!("    Problem: {desc}"))
                                 ]
                             ].align_items(Alignment::Center)
@@ -138,11 +232,67 @@ impl Application for SynchronizerUI {
             )))
         };
 
+        let space_warning = text(self.selection.status().unwrap_or_default());
+
         container(column![
+            container(profile_picker).width(Length::Fill).center_x(),
             container(header).width(Length::Fill).height(Length::Shrink).center_x().align_y(Vertical::Top),
             container(analyze).width(Length::Fill).center_x(),
             container(apply).width(Length::Fill).center_x(),
+            container(progress).width(Length::Fill).center_x(),
+            container(space_warning).width(Length::Fill).center_x(),
             container(results).width(Length::Fill).height(Length::Shrink).center_x(),
         ]).width(Length::Fill).height(Length::Fill).center_x().into()
     }
-}
\ No newline at end of file
+}
+
+impl SynchronizerUI {
+    /// Finds the differences in the background, unless a job is running.
+    fn start_analysis(&mut self) {
+        if self.job.is_some() {
+            return;
+        }
+        let (source_path, target_path) = (self.selection.source_path.clone(), self.selection.target_path.clone());
+        self.start_job("Analyzing", move |options| {
+            SynchronizerUiMessage::AnalysisFinished(analyze(&source_path, &target_path, &options, Mode::Ui))
+        });
+    }
+
+    /// Applies the selected differences in the background, unless a job is running.
+    fn start_apply(&mut self) {
+        if self.job.is_some() {
+            return;
+        }
+        let (source_path, target_path) = (self.selection.source_path.clone(), self.selection.target_path.clone());
+        let differences = self.selection.selected();
+        self.start_job("Applying", move |options| {
+            SynchronizerUiMessage::ApplyFinished(apply(&source_path, &target_path, &differences, &options, Mode::Ui))
+        });
+    }
+
+    /// Runs the work on its own thread with the options of the selection, reporting its progress until it finishes.
+    fn start_job(&mut self, description: &'static str, work: impl FnOnce(SyncOptions) -> SynchronizerUiMessage + Send + 'static) {
+        let progress = Arc::new(Progress::default());
+        let mut options = self.selection.options.clone();
+        options.progress = Some(progress.clone());
+        let (sender, receiver) = mpsc::unbounded();
+        let job_progress = progress.clone();
+        thread::spawn(move || {
+            let worker = thread::spawn(move || work(options));
+            while !worker.is_finished() {
+                thread::sleep(PROGRESS_INTERVAL);
+                sender.unbounded_send(SynchronizerUiMessage::Progress(job_progress.snapshot())).ok();
+            }
+            let finished = worker.join().unwrap_or(SynchronizerUiMessage::JobFailed);
+            sender.unbounded_send(finished).ok();
+        });
+        self.job = Some(Job { id: self.next_job_id, description, progress, last_progress: ProgressSnapshot::default(), events: Arc::new(Mutex::new(receiver)) });
+        self.next_job_id += 1;
+        self.last_result = None;
+    }
+
+    /// Ends the running job, returns whether it was cancelled.
+    fn finish_job(&mut self) -> bool {
+        self.job.take().is_some_and(|job| job.progress.is_cancelled())
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::state::state_dir;
+
+/// Directory in the state directory of the target, which keeps the files replaced or deleted by each run.
+pub(crate) const VERSIONS_DIR_NAME: &str = "versions";
+
+/// Files of the target that a run replaces or deletes are moved into a directory of that run
+/// (".directory_synchronizer/versions/2024-01-31T120000Z/relative/path") instead of being overwritten or removed.
+#[derive(Debug)]
+pub(crate) struct Versions {
+    target_dir: PathBuf,
+    run_dir: PathBuf,
+}
+
+impl Versions {
+    /// Versions of a run starting now, the directory is only created once the first file is preserved.
+    pub(crate) fn new(target_dir: &str) -> Versions {
+        //without colons, which are not allowed on FAT/NTFS; sorts chronologically
+        let name = humantime::format_rfc3339_seconds(SystemTime::now()).to_string().replace(':', "");
+        let versions_dir = versions_dir(target_dir);
+        //runs within the same second are numbered after all earlier ones, even if those were pruned already
+        let previous = runs(&versions_dir).unwrap_or_default().into_iter()
+            .filter_map(|run| match run_order(&run) { (time, n) if time == name => Some(n), _ => None })
+            .max();
+        let run_dir = match previous {
+            Some(n) => versions_dir.join(format!("{name}-{}", n + 1)),
+            None => versions_dir.join(&name),
+        };
+        Versions { target_dir: PathBuf::from(target_dir), run_dir }
+    }
+
+    /// Moves the file (or empty directory) out of the way, keeping its path relative to the target.
+    /// Missing files are ignored.
+    pub(crate) fn preserve(&self, path: &str) -> io::Result<()> {
+        let relative = Path::new(path).strip_prefix(&self.target_dir)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("not within the target: {path}")))?;
+        let preserved = self.run_dir.join(relative);
+        if let Some(parent) = preserved.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match fs::rename(path, &preserved) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+pub(crate) fn versions_dir(target_dir: &str) -> PathBuf {
+    state_dir(target_dir).join(VERSIONS_DIR_NAME)
+}
+
+/// Removes the versions of all but the `keep` most recent runs, returns the removed directories.
+pub(crate) fn prune_versions(target_dir: &str, keep: usize) -> io::Result<Vec<PathBuf>> {
+    let mut runs = runs(&versions_dir(target_dir))?;
+    runs.sort_by_cached_key(|run| run_order(run));
+    let outdated = runs.len().saturating_sub(keep);
+    runs.truncate(outdated);
+    for run in &runs {
+        fs::remove_dir_all(run)?;
+    }
+    Ok(runs)
+}
+
+/// Directories of the runs, none if there are no versions yet.
+fn runs(versions_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs::read_dir(versions_dir) {
+        Ok(entries) => Ok(entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_dir()).collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Time and number of a run ("2024-01-31T120000Z-2"), the first run of a second has number 1.
+fn run_order(run: &Path) -> (String, u32) {
+    let name = run.file_name().unwrap_or_default().to_string_lossy();
+    match name.rsplit_once('-').and_then(|(time, n)| Some((time, n.parse().ok()?))) {
+        Some((time, n)) if time.ends_with('Z') => (time.to_string(), n),
+        _ => (name.to_string(), 1),
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, error, info, warn};
+use crate::apply::ApplyReport;
+use crate::cli::Mode;
+use crate::differences::{apply_diffs_source_to_target_with_prints, find_differences_at, SourceListing};
+use crate::filters::Excludes;
+use crate::hooks::{RunHooks, sync_with_hooks};
+use crate::logging;
+use crate::options::SyncOptions;
+use crate::outcome::Outcome;
+use crate::state::STATE_DIR_NAME;
+
+/// How often the full trees are compared at least, once the inotify watch limit was reached.
+const LIMITED_RECONCILE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Changes are applied after at most this many debounce intervals, even if the source keeps changing.
+const MAX_DEBOUNCE_INTERVALS: u32 = 10;
+
+/// Paths of the source that changed, collected from inotify events.
+#[derive(Debug, Default)]
+pub(crate) struct Changes {
+    /// Paths relative to the source that were created, modified, deleted or renamed.
+    pub(crate) paths: BTreeSet<String>,
+    /// Events were lost (the event queue overflowed), only comparing the full trees finds all changes.
+    pub(crate) overflowed: bool,
+}
+
+/// Watches all directories of a source tree with inotify.
+///
+/// Directories created or renamed within the tree are watched as their events arrive.
+/// If the watch limit (fs.inotify.max_user_watches) is reached, the remaining directories stay unwatched,
+/// their changes are only found when the full trees are compared.
+pub(crate) struct SourceWatcher {
+    inotify: Inotify,
+    source_dir: String,
+    /// Watched directories, relative to the source.
+    watches: HashMap<WatchDescriptor, String>,
+    excludes: Excludes,
+    limit_reached: bool,
+    buffer: Vec<u8>,
+}
+
+impl SourceWatcher {
+    pub(crate) fn new(source_dir: &str, options: &SyncOptions) -> Result<SourceWatcher, String> {
+        let inotify = Inotify::init().map_err(|e| format!("cannot initialize inotify: {e}"))?;
+        let mut watcher = SourceWatcher {
+            inotify,
+            source_dir: source_dir.to_string(),
+            watches: HashMap::new(),
+            excludes: options.excludes.clone(),
+            limit_reached: false,
+            buffer: vec![0; 64 * 1024],
+        };
+        watcher.watch_tree("");
+        Ok(watcher)
+    }
+
+    /// Whether some directories could not be watched, because the inotify watch limit was reached.
+    pub(crate) fn limit_reached(&self) -> bool {
+        self.limit_reached
+    }
+
+    /// Watches the directory and all directories below it that are not excluded.
+    /// Directories that are already watched keep their watch.
+    pub(crate) fn watch_tree(&mut self, relative_dir: &str) {
+        let mask = WatchMask::CREATE | WatchMask::DELETE | WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::ATTRIB
+            | WatchMask::MOVED_FROM | WatchMask::MOVED_TO | WatchMask::ONLYDIR | WatchMask::DONT_FOLLOW | WatchMask::EXCL_UNLINK;
+        let root_len = self.source_dir.len();
+        let excludes = &self.excludes;
+        let walker = walkdir::WalkDir::new(Path::new(&self.source_dir).join(relative_dir))
+            .into_iter()
+            .filter_entry(|e| {
+                let relative = relative_path(e.path(), root_len);
+                e.file_type().is_dir() && relative != STATE_DIR_NAME && !excludes.is_excluded(&relative, true)
+            });
+        for entry in walker.filter_map(|e| e.ok()) {
+            match self.inotify.watches().add(entry.path(), mask) {
+                Ok(wd) => {
+                    self.watches.insert(wd, relative_path(entry.path(), root_len));
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                    if !self.limit_reached {
+                        warn!("Warning, the inotify watch limit is reached after {} directories (see fs.inotify.max_user_watches),\n    \
+                               changes in the other directories are only found when the full trees are compared.", self.watches.len());
+                    }
+                    self.limit_reached = true;
+                    return;
+                }
+                //e.g. removed again in the meantime, its parent reports that
+                Err(e) => debug!("Cannot watch '{}': {e}", entry.path().display()),
+            }
+        }
+    }
+
+    /// Stops watching the directory and all directories below it, e.g. after it was moved away.
+    fn unwatch_tree(&mut self, relative_dir: &str) {
+        let prefix = format!("{relative_dir}/");
+        let inotify = &self.inotify;
+        self.watches.retain(|wd, dir| {
+            let below = dir == relative_dir || dir.starts_with(&prefix);
+            if below {
+                //fails for deleted directories, whose watches the kernel already removed
+                inotify.watches().remove(wd.clone()).ok();
+            }
+            !below
+        });
+    }
+
+    /// Waits up to the timeout (None waits indefinitely) for a first change,
+    /// then collects further changes until none arrived for the debounce interval.
+    pub(crate) fn wait_for_changes(&mut self, timeout: Option<Duration>, debounce: Duration) -> Result<Changes, String> {
+        let mut changes = Changes::default();
+        if !self.poll(timeout)? {
+            return Ok(changes);
+        }
+        let deadline = Instant::now() + debounce * MAX_DEBOUNCE_INTERVALS;
+        loop {
+            self.read_events(&mut changes)?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !self.poll(Some(debounce.min(remaining)))? {
+                return Ok(changes);
+            }
+        }
+    }
+
+    /// Whether events arrived within the timeout.
+    fn poll(&self, timeout: Option<Duration>) -> Result<bool, String> {
+        let mut fd = libc::pollfd { fd: self.inotify.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        // SAFETY: poll on a single valid descriptor
+        match unsafe { libc::poll(&mut fd, 1, timeout) } {
+            -1 => match io::Error::last_os_error() {
+                e if e.kind() == ErrorKind::Interrupted => Ok(false),
+                e => Err(format!("cannot wait for inotify events: {e}")),
+            },
+            ready => Ok(ready > 0),
+        }
+    }
+
+    fn read_events(&mut self, changes: &mut Changes) -> Result<(), String> {
+        loop {
+            let events: Vec<(WatchDescriptor, EventMask, Option<String>)> = match self.inotify.read_events(&mut self.buffer) {
+                Ok(events) => events.map(|e| (e.wd, e.mask, e.name.map(|n| n.to_string_lossy().into_owned()))).collect(),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(format!("cannot read inotify events: {e}")),
+            };
+            for (wd, mask, name) in events {
+                if mask.contains(EventMask::Q_OVERFLOW) {
+                    changes.overflowed = true;
+                    continue;
+                }
+                if mask.contains(EventMask::IGNORED) {
+                    self.watches.remove(&wd);
+                    continue;
+                }
+                //events of the watched directory itself are also reported by its parent
+                let (Some(dir), Some(name)) = (self.watches.get(&wd), name) else { continue };
+                let path = if dir.is_empty() { name } else { format!("{dir}/{name}") };
+                let is_dir = mask.contains(EventMask::ISDIR);
+                if path == STATE_DIR_NAME || self.excludes.is_excluded(&path, is_dir) {
+                    continue;
+                }
+                if is_dir && mask.intersects(EventMask::MOVED_FROM | EventMask::DELETE) {
+                    self.unwatch_tree(&path);
+                }
+                if is_dir && mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    self.watch_tree(&path);
+                }
+                changes.paths.insert(path);
+            }
+        }
+    }
+}
+
+fn relative_path(path: &Path, root_len: usize) -> String {
+    path.to_string_lossy()[root_len..].trim_start_matches('/').to_string()
+}
+
+/// Synchronizes the targets continuously, until the process is ended.
+///
+/// Changes of the source reported by inotify are applied per path, once no further changes arrived for the debounce interval.
+/// The full trees are compared at the start, periodically (the reconcile interval) and whenever events were lost,
+/// to catch changes that were missed. Each application is a run of its own for the retention of replaced files
+/// and for the hooks, which run around each of them.
+/// Returns only if the source cannot be watched anymore.
+pub(crate) fn watch(source_dir: &str, targets: &[(&str, SyncOptions)], options: &SyncOptions) -> Outcome {
+    //watched before the first comparison, so that no change in between is missed
+    let mut watcher = match SourceWatcher::new(source_dir, options) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("{e}");
+            return Outcome::Failed;
+        }
+    };
+    info!("Watching \"{source_dir}\" for changes...");
+    let mut next_reconcile = Some(Instant::now());
+    loop {
+        if next_reconcile.is_some_and(|at| at <= Instant::now()) {
+            reconcile(source_dir, targets);
+            watcher.watch_tree("");
+            let interval = match (options.reconcile_interval, watcher.limit_reached()) {
+                (interval, true) => Some(interval.map_or(LIMITED_RECONCILE_INTERVAL, |i| i.min(LIMITED_RECONCILE_INTERVAL))),
+                (interval, false) => interval,
+            };
+            next_reconcile = interval.map(|interval| Instant::now() + interval);
+        }
+        let timeout = next_reconcile.map(|at| at.saturating_duration_since(Instant::now()));
+        let changes = match watcher.wait_for_changes(timeout, options.debounce) {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("{e}");
+                return Outcome::Failed;
+            }
+        };
+        if changes.overflowed {
+            warn!("Warning, inotify events were lost, comparing the full trees.");
+            next_reconcile = Some(Instant::now());
+        }
+        if !changes.paths.is_empty() {
+            apply_changes(source_dir, targets, &changes.paths);
+        }
+    }
+}
+
+/// Compares the full trees and applies all differences, like a sync run.
+fn reconcile(source_dir: &str, targets: &[(&str, SyncOptions)]) {
+    info!("Comparing \"{source_dir}\" with all backup directories...");
+    let source_listing = SourceListing::default();
+    for (target_dir, options) in targets {
+        for_target(target_dir, options, || {
+            let hooks = RunHooks::new(options, Mode::Watch, source_dir, target_dir);
+            let result = hooks.pre_scan().and_then(|_| sync_with_hooks(source_dir, target_dir, options, &source_listing, &hooks));
+            finish(&hooks, target_dir, result);
+        });
+    }
+}
+
+/// Applies the differences of the changed paths of the source to each target.
+pub(crate) fn apply_changes(source_dir: &str, targets: &[(&str, SyncOptions)], paths: &BTreeSet<String>) {
+    for (target_dir, options) in targets {
+        for_target(target_dir, options, || {
+            let hooks = RunHooks::new(options, Mode::Watch, source_dir, target_dir);
+            let result = hooks.pre_scan().and_then(|_| {
+                let diffs: Vec<_> = affected_paths(target_dir, paths).iter()
+                    .flat_map(|path| find_differences_at(source_dir, target_dir, path, options))
+                    .collect();
+                //the watch mode applies without asking, so nothing is a problem
+                hooks.post_scan(diffs.len(), 0);
+                hooks.pre_apply(diffs.len(), 0)?;
+                Ok(apply_diffs_source_to_target_with_prints(source_dir, target_dir, diffs.iter(), options))
+            });
+            finish(&hooks, target_dir, result);
+        });
+    }
+}
+
+/// Reports the result of an application to the target and runs the post-apply hook.
+fn finish(hooks: &RunHooks, target_dir: &str, result: Result<ApplyReport, String>) {
+    let (outcome, report) = match result {
+        Ok(report) => {
+            if report.results.is_empty() {
+                debug!("\"{target_dir}\" is up-to-date.");
+            } else {
+                info!("Backup directory \"{target_dir}\": {}", report.summary());
+            }
+            (Outcome::of_report(&report), report)
+        }
+        Err(e) => {
+            error!("{e}\nRefusing to synchronize \"{target_dir}\".");
+            (Outcome::Failed, ApplyReport::default())
+        }
+    };
+    hooks.post_apply(&report, outcome);
+}
+
+/// A failing target (even one that panics, e.g. a disconnected drive) does not stop the others or the watching.
+/// Meanwhile, messages are appended to the log file of the target, if enabled.
+fn for_target(target_dir: &str, options: &SyncOptions, synchronize: impl FnOnce()) {
+    if options.log_file {
+        if let Err(e) = logging::log_to_file(target_dir) {
+            warn!("Warning, {e}");
+        }
+    }
+    if panic::catch_unwind(AssertUnwindSafe(synchronize)).is_err() {
+        error!("Synchronizing \"{target_dir}\" failed unexpectedly, retrying with the next changes.");
+    }
+    logging::close_log_file();
+}
+
+/// The paths whose differences have to be found: of paths within each other only the outermost,
+/// paths whose parent is missing in the target are replaced by the parent, which is then copied as a whole.
+pub(crate) fn affected_paths(target_dir: &str, paths: &BTreeSet<String>) -> BTreeSet<String> {
+    let mut affected = BTreeSet::new();
+    for path in paths {
+        let mut path = Path::new(path);
+        while let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            if Path::new(target_dir).join(parent).is_dir() {
+                break;
+            }
+            path = parent;
+        }
+        affected.insert(path.to_string_lossy().into_owned());
+    }
+    let within_other = |path: &String| Path::new(path).ancestors().skip(1).any(|a| affected.contains(a.to_string_lossy().as_ref()));
+    affected.iter().filter(|path| !within_other(path)).cloned().collect()
+}